@@ -1,7 +1,7 @@
 use std::env;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Put the linker script somewhere the linker can find it
@@ -12,4 +12,54 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
     println!("cargo:rerun-if-changed=memory.x");
+
+    usb_identity(&out_dir);
+}
+
+/// Generate the USB vendor/product IDs and strings, see "USB identity" in the README
+fn usb_identity(out_dir: &Path) {
+    let vid = number("BBTRACKBALL_VID", 0x0000);
+    let pid = number("BBTRACKBALL_PID", 0x3821);
+    let manufacturer = string("BBTRACKBALL_MANUFACTURER", "JoshFTW");
+    let product = string("BBTRACKBALL_PRODUCT", "BBTrackball");
+
+    fs::write(
+        out_dir.join("usb_identity.rs"),
+        format!(
+            "pub const VID: u16 = {:#06x};\n\
+             pub const PID: u16 = {:#06x};\n\
+             pub const MANUFACTURER: &str = {:?};\n\
+             pub const PRODUCT: &str = {:?};\n",
+            vid, pid, manufacturer, product
+        ),
+    )
+    .unwrap();
+}
+
+/// A 16 bit ID from the environment, decimal or 0x prefixed hex
+fn number(name: &str, default: u16) -> u16 {
+    println!("cargo:rerun-if-env-changed={}", name);
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(_) => return default,
+    };
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .unwrap_or_else(|_| panic!("{} must be a 16 bit number, got '{}'", name, value))
+}
+
+/// A USB string descriptor from the environment
+fn string(name: &str, default: &str) -> String {
+    println!("cargo:rerun-if-env-changed={}", name);
+    let value = env::var(name).unwrap_or_else(|_| default.into());
+    // String descriptors are UTF-16 with a 2 byte header, in at most 255 bytes
+    if value.encode_utf16().count() > 126 {
+        panic!(
+            "{} is longer than the 126 characters a USB string can hold",
+            name
+        );
+    }
+    value
 }
@@ -1,6 +1,11 @@
-/* STM32F042G6 */
+/* STM32F042G6, see the layout in ../stm32f0_hid_mouse_image/src/lib.rs */
 MEMORY
 {
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 32K
-  RAM  (rwx) : ORIGIN = 0x20000000, LENGTH =  6K
+  /* The first page holds the bootloader, see ../stm32f0_hid_mouse_bootloader */
+  /* The image ends in a 4 byte CRC, added by the bbtrackball-image tool */
+  FLASH (rx) : ORIGIN = 0x08000400, LENGTH = 29K - 4
+  /* The last 2K of the 32K flash hold the settings store, see src/store.rs */
+  SETTINGS (r) : ORIGIN = 0x08007800, LENGTH = 2K
+  /* Below: the vector table copy. Above: the bootloader request word, and padding for the stack */
+  RAM  (rwx) : ORIGIN = 0x200000C0, LENGTH =  6K - 0xC0 - 8
 }
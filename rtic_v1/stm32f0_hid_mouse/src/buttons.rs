@@ -0,0 +1,19 @@
+//! Compile-time defaults for what the aux buttons do. The host can remap them at runtime
+//! through the settings feature report.
+
+use bbtrackball_settings::{Action, BUTTONS};
+
+/// Left mouse button bit in `MouseReport::buttons`
+pub const MOUSE_LEFT: u8 = 0x01;
+
+/// Default button to action table, indexed by `AuxButton`.
+/// An action is a mouse button, a key plus modifiers (e.g. `Action::Key { modifier: 0x01,
+/// keycode: 0x06 }` for Ctrl+C), a consumer control usage (e.g.
+/// `Action::Consumer(MediaKey::PlayPause as u16)`), the scroll mode toggle or one of the
+/// drag-lock and double-click gestures.
+pub const BUTTON_ACTIONS: [Action; BUTTONS] = [
+    // Button3 (PA15)
+    Action::Mouse(MOUSE_LEFT),
+    // Button5 (PB3)
+    Action::ToggleScroll,
+];
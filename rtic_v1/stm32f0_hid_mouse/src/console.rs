@@ -0,0 +1,175 @@
+//! Debug console on a USB CDC-ACM serial port.
+//!
+//! Everything logged with `log!` still goes out over RTT, and is also queued up here until
+//! `usb_handler` gets around to writing it to the serial port. Lines typed into the serial port
+//! are handed to the `console_command` task.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use bbtrackball_pipeline::Direction;
+use cortex_m::interrupt::{free as disable_interrupts, Mutex};
+use heapless::{Deque, String};
+use stm32f0xx_hal::{pac, usb};
+use usbd_serial::SerialPort;
+
+/// Bytes of log output kept around while nobody is reading the serial port
+const LOG_CAPACITY: usize = 512;
+
+/// Longest command line accepted on the serial port
+pub const LINE_CAPACITY: usize = 32;
+
+/// A command line typed into the serial port
+pub type Line = String<LINE_CAPACITY>;
+
+static LOG: Mutex<RefCell<Deque<u8, LOG_CAPACITY>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// Log to RTT and mirror the same line to the serial console
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::console::write_log(format_args!($($arg)*))
+    };
+}
+pub(crate) use log;
+
+/// Log to RTT only, for code that also runs from `usb_handler`: logging to the console from
+/// there would pend that interrupt again
+#[cfg(feature = "verbose-log")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::console::write_trace(format_args!($($arg)*))
+    };
+}
+#[cfg(feature = "verbose-log")]
+pub(crate) use trace;
+
+/// Counts of the events seen since power on, or since the last `reset`
+#[derive(Default)]
+pub struct Counters {
+    pub tb_left: u32,
+    pub tb_up: u32,
+    pub tb_right: u32,
+    pub tb_down: u32,
+    pub aux_presses: u32,
+}
+
+impl Counters {
+    /// Count a trackball pulse
+    pub fn trackball(&mut self, direction: Direction) {
+        let counter = match direction {
+            Direction::Left => &mut self.tb_left,
+            Direction::Up => &mut self.tb_up,
+            Direction::Right => &mut self.tb_right,
+            Direction::Down => &mut self.tb_down,
+        };
+        *counter += 1;
+    }
+}
+
+/// Appends to the log queue, dropping the oldest bytes once it is full
+struct LogWriter<'a>(&'a mut Deque<u8, LOG_CAPACITY>);
+
+impl Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.0.is_full() {
+                self.0.pop_front();
+            }
+            self.0.push_back(byte).ok();
+        }
+        Ok(())
+    }
+}
+
+/// Displays a string as is, for `log!` arguments.
+///
+/// `Display` for `str` and `bool` honours width and fill, which pulls `Formatter::pad` into the
+/// firmware. No log line pads its arguments, so this writes them straight through instead.
+pub struct Plain<'a>(pub &'a str);
+
+impl<'a> Plain<'a> {
+    pub fn bool(value: bool) -> Self {
+        Plain(if value { "true" } else { "false" })
+    }
+}
+
+impl fmt::Display for Plain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Use `log!` rather than calling this directly. Formatting the arguments only once, in here,
+/// rather than at every `log!` keeps the firmware small enough to fit.
+pub fn write_log(args: fmt::Arguments) {
+    write_trace(args);
+    mirror(args);
+}
+
+/// Use `trace!` rather than calling this directly
+pub fn write_trace(args: fmt::Arguments) {
+    rtt_target::rprintln!("{}", args);
+}
+
+/// Queue a log line for the serial console
+fn mirror(args: fmt::Arguments) {
+    disable_interrupts(|cs| {
+        let mut log = LOG.borrow(cs).borrow_mut();
+        let mut writer = LogWriter(&mut log);
+        writer.write_fmt(args).ok();
+        writer.write_str("\r\n").ok();
+    });
+
+    // Have usb_handler write it out
+    rtic::pend(pac::Interrupt::USB);
+}
+
+/// Write as much of the queued log as the serial port will take.
+/// Called from `usb_handler` only, as it must not log itself.
+pub fn flush(serial: &mut SerialPort<'static, usb::UsbBusType>) {
+    // Hold on to the log until a terminal is actually listening
+    if !serial.dtr() {
+        return;
+    }
+
+    disable_interrupts(|cs| {
+        let mut log = LOG.borrow(cs).borrow_mut();
+        loop {
+            let written = match serial.write(log.as_slices().0) {
+                Ok(written) if written > 0 => written,
+                _ => break,
+            };
+            for _ in 0..written {
+                log.pop_front();
+            }
+        }
+    });
+}
+
+/// Collect bytes read from the serial port into `line`, without leading or trailing blanks.
+/// Returns true once a full line has been received.
+///
+/// Trimming here rather than with `str::trim` keeps its Unicode tables out of the firmware.
+pub fn receive(line: &mut Line, byte: u8) -> bool {
+    match byte {
+        b'\r' | b'\n' => {
+            while let Some(b' ' | b'\t') = line.as_bytes().last() {
+                line.truncate(line.len() - 1);
+            }
+            !line.is_empty()
+        }
+        b' ' | b'\t' if line.is_empty() => false,
+        // Backspace and delete. The line is ASCII only, so a byte is a whole character.
+        0x08 | 0x7f => {
+            line.truncate(line.len().saturating_sub(1));
+            false
+        }
+        // Overlong lines are truncated, and rejected as unknown commands later on
+        _ if byte.is_ascii() => {
+            line.push(byte as char).ok();
+            false
+        }
+        // Anything else would take two bytes as a `char`, and no command uses it
+        _ => false,
+    }
+}
@@ -0,0 +1,121 @@
+//! DFU runtime interface, and the handshake with the bootloader.
+//!
+//! A DFU_DETACH from the host (e.g. `dfu-util -e`) makes the firmware reset into the bootloader
+//! with a request to start the USB DFU bootloader in system memory, which then takes the new
+//! image. The flash and RAM layout shared with the bootloader lives in the `bbtrackball-image`
+//! crate.
+
+use core::ptr;
+
+use bbtrackball_image::{DFU_REQUEST, IMAGE_START, REQUEST, VECTOR_TABLE_COPY, VECTOR_TABLE_LEN};
+use stm32f0xx_hal::pac;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+const DFU_DESC_DESCTYPE_FUNCTIONAL: u8 = 0x21;
+const DFU_REQ_DETACH: u8 = 0x00;
+const DFU_REQ_GETSTATUS: u8 = 0x03;
+const DFU_REQ_GETSTATE: u8 = 0x05;
+
+/// DFU functional descriptor body: detaches by itself (bitWillDetach) and can download, waits
+/// 255 ms for the detach, 2K transfers like the system memory bootloader, DFU 1.1a
+const DFU_DESCRIPTOR: [u8; 7] = [0x09, 0xFF, 0x00, 0x00, 0x08, 0x1A, 0x01];
+
+/// GET_STATUS answer: status OK, no poll timeout, state appIDLE, no status string
+const DFU_STATUS_APP_IDLE: [u8; 6] = [0; 6];
+
+pub struct DfuRuntime {
+    if_num: InterfaceNumber,
+    /// The host asked to detach, until picked up
+    detach: bool,
+}
+
+impl DfuRuntime {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> DfuRuntime {
+        DfuRuntime {
+            if_num: alloc.interface(),
+            detach: false,
+        }
+    }
+
+    /// Did the host ask to detach since the last call
+    pub fn take_detach(&mut self) -> bool {
+        core::mem::take(&mut self.detach)
+    }
+
+    /// Is this control request for our interface?
+    fn is_ours(&self, req: &control::Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.if_num) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.if_num,
+            USB_CLASS_APPLICATION_SPECIFIC,
+            DFU_SUBCLASS,
+            DFU_PROTOCOL_RUNTIME,
+        )?;
+        writer.write(DFU_DESC_DESCTYPE_FUNCTIONAL, &DFU_DESCRIPTOR)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if !self.is_ours(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            DFU_REQ_GETSTATUS => xfer.accept_with_static(&DFU_STATUS_APP_IDLE).ok(),
+            DFU_REQ_GETSTATE => xfer.accept_with_static(&DFU_STATUS_APP_IDLE[4..5]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if !self.is_ours(xfer.request()) {
+            return;
+        }
+
+        match xfer.request().request {
+            DFU_REQ_DETACH => {
+                self.detach = true;
+                xfer.accept().ok()
+            }
+            _ => xfer.reject().ok(),
+        };
+    }
+}
+
+/// Copy the vector table to the start of RAM and map RAM at address 0. The Cortex-M0 always
+/// takes its vectors from there, where the bootloader is otherwise.
+/// Needs the SYSCFG clock, and has to run before any interrupt is enabled.
+pub fn relocate_vector_table(syscfg: &pac::SYSCFG) {
+    let from = IMAGE_START as *const u32;
+    let to = VECTOR_TABLE_COPY as *mut u32;
+    for i in 0..VECTOR_TABLE_LEN / 4 {
+        // Safety: the vector table is at the start of the image, and memory.x keeps the
+        // firmware out of the RAM it is copied to
+        unsafe { ptr::write_volatile(to.add(i), ptr::read_volatile(from.add(i))) };
+    }
+    syscfg.cfgr1.modify(|_, w| w.mem_mode().sram());
+}
+
+/// Reset into the bootloader, asking it to start the DFU bootloader in system memory.
+/// Waits 10 ms first, for the host to collect the status stage of the DFU_DETACH. The USB
+/// peripheral answers it on its own, so this can block the USB interrupt.
+pub fn reboot_to_bootloader(sysclk_hz: u32) -> ! {
+    cortex_m::asm::delay(sysclk_hz / 100);
+    // Safety: the request word is reserved RAM, see memory.x
+    unsafe { ptr::write_volatile(REQUEST as *mut u32, DFU_REQUEST) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,72 @@
+//! The HID interfaces, as the report sink of the input pipeline
+
+#[cfg(feature = "verbose-log")]
+use crate::console::trace;
+use bbtrackball_pipeline::{Busy, MouseReport, ReportSink};
+use stm32f0xx_hal::usb;
+use usb_device::UsbError;
+use usbd_hid::{
+    descriptor::{KeyboardReport, MediaKeyboardReport},
+    hid_class::{HIDClass, HidProtocolMode},
+};
+
+type Class = HIDClass<'static, usb::UsbBusType>;
+
+/// Borrows the mouse, keyboard and consumer control interfaces for the duration of a lock.
+pub struct HidSink<'a> {
+    pub mouse: &'a mut Class,
+    pub keyboard: &'a mut Class,
+    pub consumer: &'a mut Class,
+}
+
+/// Only a full endpoint is worth trying again, anything else (e.g. not configured yet) drops the report
+fn busy(result: usb_device::Result<usize>) -> Result<(), Busy> {
+    match result {
+        Err(UsbError::WouldBlock) => Err(Busy),
+        _ => Ok(()),
+    }
+}
+
+impl ReportSink for HidSink<'_> {
+    fn mouse(&mut self, report: &MouseReport) -> Result<(), Busy> {
+        let hid = &mut *self.mouse;
+        // usbd-hid refuses `push_input` on a boot interface outside boot protocol, so both
+        // reports are laid out by hand in the order of `MouseReport::desc()`.
+        match hid.get_protocol_mode() {
+            Ok(HidProtocolMode::Boot) => {
+                // The boot report is just buttons, x and y. Wheel and pan are dropped.
+                #[cfg(feature = "verbose-log")]
+                trace!("Sending boot mouse report...");
+                busy(hid.push_raw_input(&[report.buttons, report.x as u8, report.y as u8]))
+            }
+            _ => {
+                #[cfg(feature = "verbose-log")]
+                trace!("Sending mouse report...");
+                busy(hid.push_raw_input(&[
+                    report.buttons,
+                    report.x as u8,
+                    report.y as u8,
+                    report.wheel as u8,
+                    report.pan as u8,
+                ]))
+            }
+        }
+    }
+
+    fn keyboard(&mut self, modifier: u8, keycode: u8) -> Result<(), Busy> {
+        #[cfg(feature = "verbose-log")]
+        trace!("Sending keyboard report...");
+        busy(self.keyboard.push_input(&KeyboardReport {
+            modifier,
+            reserved: 0,
+            leds: 0,
+            keycodes: [keycode, 0, 0, 0, 0, 0],
+        }))
+    }
+
+    fn consumer(&mut self, usage_id: u16) -> Result<(), Busy> {
+        #[cfg(feature = "verbose-log")]
+        trace!("Sending consumer control report...");
+        busy(self.consumer.push_input(&MediaKeyboardReport { usage_id }))
+    }
+}
@@ -0,0 +1,23 @@
+//! USB identity: IDs and strings chosen at build time by `build.rs`, and a serial number
+//! unique to each chip.
+
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
+
+/// Address of the 96 bit unique device ID, see RM0091 33.1
+const UID: usize = 0x1FFF_F7AC;
+
+/// Length of the serial number: the device ID in hex
+pub const SERIAL_LEN: usize = 24;
+
+/// Write the unique device ID into `buf` as upper case hex, for use as the USB serial number
+pub fn serial_number(buf: &mut [u8; SERIAL_LEN]) -> &str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    // Safety: the device ID is read-only and always mapped
+    let uid = unsafe { core::ptr::read_volatile(UID as *const [u8; SERIAL_LEN / 2]) };
+    for (pair, byte) in buf.chunks_mut(2).zip(uid.iter()) {
+        pair[0] = HEX[(byte >> 4) as usize];
+        pair[1] = HEX[(byte & 0xF) as usize];
+    }
+    // Safety: only ASCII hex digits were written
+    unsafe { core::str::from_utf8_unchecked(buf) }
+}
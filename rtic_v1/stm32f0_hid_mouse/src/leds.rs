@@ -0,0 +1,150 @@
+//! The four trackball LEDs, dimmed with PWM on TIM2 channels 1 to 4 (PA0 to PA3).
+//!
+//! What they show is worked out from the trackball mode, the settings and a few bits of state
+//! kept here: whether the bus is suspended, whether caps lock is on, and any colour set by the
+//! host through the settings interface's output report.
+
+use bbtrackball_pipeline::TrackballMode;
+use bbtrackball_settings::{Color, LedMode, Settings};
+use stm32f0xx_hal::{
+    gpio::gpioa::{PA0, PA1, PA2, PA3},
+    gpio::{Alternate, AF2},
+    pac,
+};
+
+/// Timer period. Brightness is squared into the duty cycle as a rough gamma correction, which
+/// at 48 MHz gives a PWM frequency of about 740 Hz.
+const PERIOD: u32 = 255 * 255;
+
+/// Colour in pointer mode
+const POINTER_COLOR: Color = Color::new(0, 0, 0, 255);
+/// Colour in scroll mode
+const SCROLL_COLOR: Color = Color::new(0, 0, 255, 0);
+
+pub type Pins = (
+    PA0<Alternate<AF2>>,
+    PA1<Alternate<AF2>>,
+    PA2<Alternate<AF2>>,
+    PA3<Alternate<AF2>>,
+);
+
+/// A change in what the LEDs should show, besides the mode and settings
+#[derive(Clone, Copy)]
+pub enum LedChange {
+    /// The host turned caps lock on or off
+    CapsLock(bool),
+    /// The host set a colour, or handed the LEDs back with `None`
+    HostColor(Option<Color>),
+    /// The host suspended or resumed the bus
+    Suspended(bool),
+}
+
+pub struct Leds {
+    tim: pac::TIM2,
+    _pins: Pins,
+    /// Colour set by the host, shown instead of the mode
+    host_color: Option<Color>,
+    caps_lock: bool,
+    suspended: bool,
+}
+
+impl Leds {
+    pub fn new(tim: pac::TIM2, pins: Pins) -> Self {
+        // Safety: only enables the TIM2 clock, which nothing else touches after `init`
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+
+        // PWM mode 1 with preloaded compare registers on all four channels, active high
+        tim.ccmr1_output().write(|w| {
+            w.oc1m().pwm_mode1().oc1pe().set_bit();
+            w.oc2m().pwm_mode1().oc2pe().set_bit()
+        });
+        tim.ccmr2_output().write(|w| {
+            w.oc3m().pwm_mode1().oc3pe().set_bit();
+            w.oc4m().pwm_mode1().oc4pe().set_bit()
+        });
+        tim.ccer.write(|w| {
+            w.cc1e().set_bit();
+            w.cc2e().set_bit();
+            w.cc3e().set_bit();
+            w.cc4e().set_bit()
+        });
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.arr.write(|w| w.arr().bits(PERIOD));
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+
+        Leds {
+            tim,
+            _pins: pins,
+            host_color: None,
+            caps_lock: false,
+            suspended: false,
+        }
+    }
+
+    /// Fade each LED in and out in turn. Blocks for about a second, so only call from `init`.
+    pub fn startup_animation(&mut self) {
+        let channels = [
+            Color::new(255, 0, 0, 0),
+            Color::new(0, 255, 0, 0),
+            Color::new(0, 0, 255, 0),
+            Color::new(0, 0, 0, 255),
+        ];
+        for full in channels {
+            for step in (0..=255).chain((0..255).rev()) {
+                self.write(scale(full, step));
+                cortex_m::asm::delay(48_000_000 / 2000);
+            }
+        }
+        self.write(Color::OFF);
+    }
+
+    pub fn change(&mut self, change: LedChange) {
+        match change {
+            LedChange::CapsLock(caps_lock) => self.caps_lock = caps_lock,
+            LedChange::HostColor(color) => self.host_color = color,
+            LedChange::Suspended(suspended) => self.suspended = suspended,
+        }
+    }
+
+    /// Update the LEDs: off while suspended, otherwise the host's colour if it set one, or white
+    /// for pointer and blue for scroll mode. Caps lock adds red to the latter.
+    pub fn show(&mut self, mode: TrackballMode, settings: &Settings) {
+        let color = match (self.suspended, self.host_color, settings.led_mode) {
+            (true, _, _) => Color::OFF,
+            (false, Some(color), _) => color,
+            (false, None, LedMode::Off) => Color::OFF,
+            (false, None, LedMode::Mode) => {
+                let mut color = match mode {
+                    TrackballMode::Pointer => POINTER_COLOR,
+                    TrackballMode::Scroll => SCROLL_COLOR,
+                };
+                if self.caps_lock {
+                    color.red = 255;
+                }
+                color
+            }
+        };
+        self.write(color);
+    }
+
+    fn write(&mut self, color: Color) {
+        let duty = |brightness: u8| brightness as u32 * brightness as u32;
+        self.tim.ccr1.write(|w| w.ccr().bits(duty(color.red)));
+        self.tim.ccr2.write(|w| w.ccr().bits(duty(color.green)));
+        self.tim.ccr3.write(|w| w.ccr().bits(duty(color.blue)));
+        self.tim.ccr4.write(|w| w.ccr().bits(duty(color.white)));
+    }
+}
+
+/// `color` at `step`/255 of its brightness
+fn scale(color: Color, step: u8) -> Color {
+    let scale = |brightness: u8| (brightness as u16 * step as u16 / 255) as u8;
+    Color::new(
+        scale(color.red),
+        scale(color.green),
+        scale(color.blue),
+        scale(color.white),
+    )
+}
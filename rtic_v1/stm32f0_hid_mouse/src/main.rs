@@ -8,11 +8,10 @@
 use cortex_m::interrupt::free as disable_interrupts;
 use panic_halt as _;
 use rtic::app;
-use rtic::{Exclusive, Mutex};
-use rtt_target::{rprintln, rtt_init_print};
+use rtt_target::rtt_init_print;
 
 use stm32f0xx_hal::{
-    gpio::gpioa::{PA0, PA1, PA15, PA2, PA3, PA4, PA5, PA6, PA7},
+    gpio::gpioa::{PA15, PA4, PA5, PA6, PA7},
     gpio::gpiob::{PB1, PB3, PB4},
     gpio::{Input, Output, PullUp, PushPull},
     pac,
@@ -22,38 +21,99 @@ use stm32f0xx_hal::{
 
 use usb_device::{bus::UsbBusAllocator, prelude::*};
 use usbd_hid::{
-    descriptor::{MouseReport, SerializedDescriptor},
-    hid_class::HIDClass,
+    descriptor::{KeyboardReport, MediaKeyboardReport, MouseReport, SerializedDescriptor},
+    hid_class::{
+        HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidProtocolMode, HidSubClass,
+        ProtocolModeConfig,
+    },
 };
 
-#[app(device = stm32f0xx_hal::pac, peripherals = true)]
+use systick_monotonic::{fugit::Duration, Systick};
+use usbd_serial::SerialPort;
+
+use bbtrackball_pipeline::exti;
+use bbtrackball_pipeline::{AuxButton, Pipeline};
+use bbtrackball_settings::{LedMode, Settings};
+
+mod buttons;
+mod console;
+#[cfg(feature = "verbose-log")]
+use console::trace;
+use console::{log, Counters, Line, Plain};
+mod dfu;
+use dfu::DfuRuntime;
+mod hid;
+use hid::HidSink;
+mod identity;
+mod leds;
+use leds::{LedChange, Leds};
+mod power;
+mod settings_hid;
+use settings_hid::SettingsClass;
+mod store;
+use store::SettingsStore;
+
+/// Caps lock bit in the keyboard LED output report
+const CAPS_LOCK: u8 = 0x02;
+
+/// Settings in use while there are no valid ones saved in flash
+const DEFAULT_SETTINGS: Settings = Settings {
+    // Pointer movement per trackball pulse
+    pointer_sensitivity: 5,
+    // Wheel/pan movement per trackball pulse while in scroll mode
+    scroll_sensitivity: 1,
+    led_mode: LedMode::Mode,
+    buttons: buttons::BUTTON_ACTIONS,
+    tap_ms: Settings::DEFAULT_TAP_MS,
+    click_ms: Settings::DEFAULT_CLICK_MS,
+};
+
+/// System clock, which also drives the monotonic timer
+const SYSCLK_HZ: u32 = 48_000_000;
+
+#[app(device = stm32f0xx_hal::pac, peripherals = true, dispatchers = [CEC_CAN])]
 mod app {
     use super::*;
 
+    /// Millisecond timer for the aux button gestures
+    #[monotonic(binds = SysTick, default = true)]
+    type MonoTimer = Systick<1000>;
+
     #[local]
     struct Local {
         usr_led: PB1<Output<PushPull>>,
         usb_device: UsbDevice<'static, usb::UsbBusType>,
+        usb_settings: SettingsClass<'static, usb::UsbBusType>,
+        usb_dfu: DfuRuntime,
+        store: SettingsStore,
+        scb: cortex_m::peripheral::SCB,
     }
 
     #[shared]
     struct Shared {
         usb_hid: HIDClass<'static, usb::UsbBusType>,
+        usb_keyboard: HIDClass<'static, usb::UsbBusType>,
+        usb_consumer: HIDClass<'static, usb::UsbBusType>,
+        usb_serial: SerialPort<'static, usb::UsbBusType>,
+        counters: Counters,
         exti: pac::EXTI,
-        _button3: PA15<Input<PullUp>>,
+        /// Trackball mode, held mouse buttons and movement not sent yet
+        pipeline: Pipeline,
+        settings: Settings,
+        button3: PA15<Input<PullUp>>,
         _button4: PB4<Input<PullUp>>,
-        _button5: PB3<Input<PullUp>>,
+        button5: PB3<Input<PullUp>>,
         _tb_left: PA4<Input<PullUp>>,
         _tb_up: PA5<Input<PullUp>>,
         _tb_right: PA6<Input<PullUp>>,
         _tb_down: PA7<Input<PullUp>>,
-        bbled_red: PA0<Output<PushPull>>,
-        bbled_grn: PA1<Output<PushPull>>,
-        bbled_blu: PA2<Output<PushPull>>,
-        bbled_wht: PA3<Output<PushPull>>,
+        leds: Leds,
     }
 
-    #[init(local = [USB_BUS: Option<UsbBusAllocator<usb::UsbBusType>> = None])]
+    #[init(local = [
+        USB_BUS: Option<UsbBusAllocator<usb::UsbBusType>> = None,
+        SERIAL: [u8; identity::SERIAL_LEN] = [0; identity::SERIAL_LEN],
+    ])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         // RTT handler
         rtt_init_print!();
@@ -64,20 +124,23 @@ mod app {
         // This enables clock for SYSCFG and remaps USB pins to PA9 and PA10.
         usb::remap_pins(&mut dp.RCC, &mut dp.SYSCFG);
 
-        rprintln!("Initializing peripherals");
+        // Take the interrupts over from the bootloader, before RTIC enables them
+        dfu::relocate_vector_table(&dp.SYSCFG);
+
+        log!("Initializing peripherals");
         let mut rcc = dp
             .RCC
             .configure()
             .usbsrc(stm32f0xx_hal::rcc::USBClockSource::HSI48)
             .hsi48()
             .enable_crs(dp.CRS)
-            .sysclk(48.mhz())
+            .sysclk(SYSCLK_HZ.hz())
             .pclk(24.mhz())
             .freeze(&mut dp.FLASH);
 
         // Set up GPIO registers for USR LED and Buttons
         let gpiob = dp.GPIOB.split(&mut rcc);
-        let (usr_led, _button4, _button5) = disable_interrupts(|cs| {
+        let (usr_led, _button4, button5) = disable_interrupts(|cs| {
             (
                 gpiob.pb1.into_push_pull_output(cs),
                 gpiob.pb4.into_pull_up_input(cs),
@@ -96,15 +159,15 @@ mod app {
             _tb_up,
             _tb_right,
             _tb_down,
-            _button3,
+            button3,
             usb_dm,
             usb_dp,
         ) = disable_interrupts(|cs| {
             (
-                gpioa.pa0.into_push_pull_output(cs),
-                gpioa.pa1.into_push_pull_output(cs),
-                gpioa.pa2.into_push_pull_output(cs),
-                gpioa.pa3.into_push_pull_output(cs),
+                gpioa.pa0.into_alternate_af2(cs),
+                gpioa.pa1.into_alternate_af2(cs),
+                gpioa.pa2.into_alternate_af2(cs),
+                gpioa.pa3.into_alternate_af2(cs),
                 gpioa.pa4.into_pull_up_input(cs),
                 gpioa.pa5.into_pull_up_input(cs),
                 gpioa.pa6.into_pull_up_input(cs),
@@ -116,7 +179,23 @@ mod app {
         });
 
         // Power on bbled dance
-        //bbled_red.toggle().ok();
+        let mut leds = Leds::new(dp.TIM2, (bbled_red, bbled_grn, bbled_blu, bbled_wht));
+        leds.startup_animation();
+
+        // Start out as a regular pointing device
+        let pipeline = Pipeline::new();
+        let store = SettingsStore::new(dp.FLASH);
+        let settings = match store.load() {
+            Some(settings) => {
+                log!("Loaded settings from flash");
+                settings
+            }
+            None => {
+                log!("No valid settings in flash, using defaults");
+                DEFAULT_SETTINGS
+            }
+        };
+        leds.show(pipeline.mode(), &settings);
 
         // Enable external interrupt for 3 aux buttons...
         dp.SYSCFG.exticr1.write(|w| w.exti3().pb3());
@@ -135,7 +214,9 @@ mod app {
             w.mr5().set_bit();
             w.mr6().set_bit();
             w.mr7().set_bit();
-            w.mr15().set_bit()
+            w.mr15().set_bit();
+            // USB wakeup, to get out of stop mode while suspended
+            w.mr18().set_bit()
         });
 
         // Set interrupt rising trigger
@@ -148,47 +229,86 @@ mod app {
             w.tr15().set_bit()
         });
 
+        // Aux buttons also trigger on falling edge, so that presses and releases can be told apart
+        dp.EXTI.ftsr.write(|w| {
+            w.tr3().set_bit();
+            w.tr15().set_bit()
+        });
+
         let usb = usb::Peripheral {
             usb: dp.USB,
             pin_dm: usb_dm,
             pin_dp: usb_dp,
         };
 
-        rprintln!("Preparing HID mouse...");
+        log!("Preparing HID mouse, keyboard and consumer control...");
 
         let usb_bus = ctx.local.USB_BUS;
         *usb_bus = Some(usb::UsbBus::new(usb));
 
-        let usb_hid = HIDClass::new(usb_bus.as_ref().unwrap(), MouseReport::desc(), 60);
-
-        rprintln!("Defining USB parameters...");
-        let usb_device = UsbDeviceBuilder::new(usb_bus.as_ref().unwrap(), UsbVidPid(0, 0x3821))
-            .manufacturer("JoshFTW")
-            .product("BBTrackball")
-            .serial_number("RustFW")
-            .device_class(0x00)
-            .device_sub_class(0x00)
-            .device_protocol(0x00)
-            .build();
-
-        rprintln!("Instantiating dp.EXTI...");
+        // The mouse interface is a boot interface, so that BIOS/UEFI setups can use it without
+        // parsing the report descriptor. The host picks boot or report protocol with SET_PROTOCOL.
+        let usb_hid = HIDClass::new_with_settings(
+            usb_bus.as_ref().unwrap(),
+            MouseReport::desc(),
+            60,
+            HidClassSettings {
+                subclass: HidSubClass::Boot,
+                protocol: HidProtocol::Mouse,
+                config: ProtocolModeConfig::DefaultBehavior,
+                locale: HidCountryCode::NotSupported,
+            },
+        );
+        let usb_keyboard = HIDClass::new(usb_bus.as_ref().unwrap(), KeyboardReport::desc(), 60);
+        let usb_consumer =
+            HIDClass::new_ep_in(usb_bus.as_ref().unwrap(), MediaKeyboardReport::desc(), 60);
+
+        log!("Preparing serial console...");
+        let usb_serial = SerialPort::new(usb_bus.as_ref().unwrap());
+
+        log!("Preparing settings interface...");
+        let usb_settings = SettingsClass::new(usb_bus.as_ref().unwrap(), &settings);
+        let usb_dfu = DfuRuntime::new(usb_bus.as_ref().unwrap());
+
+        log!("Defining USB parameters...");
+        let serial_number = identity::serial_number(ctx.local.SERIAL);
+        log!("Serial number {}", Plain(serial_number));
+        let usb_device = UsbDeviceBuilder::new(
+            usb_bus.as_ref().unwrap(),
+            UsbVidPid(identity::VID, identity::PID),
+        )
+        .manufacturer(identity::MANUFACTURER)
+        .product(identity::PRODUCT)
+        .serial_number(serial_number)
+        .supports_remote_wakeup(true)
+        // Miscellaneous device using interface association descriptors, needed
+        // for hosts to bind the CDC-ACM interfaces of a composite device
+        .device_class(0xEF)
+        .device_sub_class(0x02)
+        .device_protocol(0x01)
+        .build();
+
+        log!("Instantiating dp.EXTI...");
         let exti = dp.EXTI;
 
-        rprintln!("Defining shared resources...");
+        log!("Defining shared resources...");
         let shared = Shared {
             usb_hid,
+            usb_keyboard,
+            usb_consumer,
+            usb_serial,
+            counters: Counters::default(),
             exti,
-            _button3,
+            pipeline,
+            settings,
+            button3,
             _button4,
-            _button5,
+            button5,
             _tb_left,
             _tb_up,
             _tb_right,
             _tb_down,
-            bbled_red,
-            bbled_grn,
-            bbled_blu,
-            bbled_wht,
+            leds,
         };
 
         (
@@ -196,127 +316,359 @@ mod app {
             Local {
                 usr_led,
                 usb_device,
+                usb_settings,
+                usb_dfu,
+                store,
+                scb: ctx.core.SCB,
             },
-            init::Monotonics(),
+            init::Monotonics(Systick::new(ctx.core.SYST, SYSCLK_HZ)),
         )
     }
 
-    #[idle]
-    fn idle(_: idle::Context) -> ! {
+    #[idle(local = [scb])]
+    fn idle(ctx: idle::Context) -> ! {
         loop {
-            cortex_m::asm::nop();
-            cortex_m::asm::wfi();
+            // Sleeps until the next interrupt, in stop mode while the bus is suspended
+            power::wait(ctx.local.scb);
         }
     }
 
+    /// Aux button 5 (PB3)
     #[task(binds = EXTI2_3, shared = [exti])]
     fn exti2_3_interrupt(mut ctx: exti2_3_interrupt::Context) {
-        rprintln!("Interrupts happening on EXTI2_3");
+        #[cfg(feature = "verbose-log")]
+        log!("Interrupts happening on EXTI2_3");
+        wake_host();
+
+        let pr = ctx.shared.exti.lock(|exti| exti.pr.read().bits());
+        for (bit, input) in exti::pending(pr & exti::EXTI2_3) {
+            // Clear interrupt. Safety: writing a 1 clears the pending bit of that line only.
+            ctx.shared
+                .exti
+                .lock(|exti| exti.pr.write(|w| unsafe { w.bits(bit) }));
+            if let exti::Input::Button(button) = input {
+                aux_button::spawn(button).ok();
+            }
+        }
+    }
 
-        match ctx.shared.exti.lock(|exti| exti.pr.read().bits()) {
-            0x8 => {
-                rprintln!("PB3 triggered");
-                ctx.shared
-                    .exti
-                    .lock(|exti| exti.pr.write(|w| w.pif3().set_bit())); // Clear interrupt
+    /// Trackball pulses and aux button 3 (PA15)
+    #[task(binds = EXTI4_15, local = [usr_led], shared = [exti, pipeline, settings, counters, usb_hid, usb_keyboard, usb_consumer])]
+    fn exti_4_15_interrupt(mut ctx: exti_4_15_interrupt::Context) {
+        #[cfg(feature = "verbose-log")]
+        log!("Interrupts happening on EXTI4_15");
+        wake_host();
+
+        let pr = ctx.shared.exti.lock(|exti| exti.pr.read().bits());
+        for (bit, input) in exti::pending(pr & exti::EXTI4_15) {
+            // Clear interrupt. Safety: writing a 1 clears the pending bit of that line only.
+            ctx.shared
+                .exti
+                .lock(|exti| exti.pr.write(|w| unsafe { w.bits(bit) }));
+            match input {
+                exti::Input::Trackball(direction) => {
+                    #[cfg(feature = "verbose-log")]
+                    log!("{:?} pulse", direction);
+                    ctx.shared
+                        .counters
+                        .lock(|counters| counters.trackball(direction));
+                    (
+                        &mut ctx.shared.pipeline,
+                        &mut ctx.shared.settings,
+                        &mut ctx.shared.usb_hid,
+                        &mut ctx.shared.usb_keyboard,
+                        &mut ctx.shared.usb_consumer,
+                    )
+                        .lock(
+                            |pipeline, settings, mouse, keyboard, consumer| {
+                                let mut sink = HidSink {
+                                    mouse,
+                                    keyboard,
+                                    consumer,
+                                };
+                                pipeline.trackball(direction, settings, &mut sink);
+                            },
+                        );
+                }
+                exti::Input::Button(button) => {
+                    aux_button::spawn(button).ok();
+                }
             }
+            ctx.local.usr_led.toggle().ok();
+        }
+    }
 
-            _ => rprintln!("Some other bits were pushed around on EXTI2_3 ;)"),
+    /// Carry out the action bound to an aux button, see `buttons::BUTTON_ACTIONS`
+    #[task(capacity = 4, shared = [pipeline, settings, counters, button3, button5, usb_hid, usb_keyboard, usb_consumer, leds])]
+    fn aux_button(mut ctx: aux_button::Context, button: AuxButton) {
+        // Buttons are pulled up, so low means pressed
+        let pressed = match button {
+            AuxButton::Button3 => ctx
+                .shared
+                .button3
+                .lock(|button| button.is_low() == Ok(true)),
+            AuxButton::Button5 => ctx
+                .shared
+                .button5
+                .lock(|button| button.is_low() == Ok(true)),
+        };
+        log!("{:?} pressed: {}", button, Plain::bool(pressed));
+        if pressed {
+            ctx.shared
+                .counters
+                .lock(|counters| counters.aux_presses += 1);
         }
+
+        (
+            ctx.shared.pipeline,
+            ctx.shared.settings,
+            ctx.shared.usb_hid,
+            ctx.shared.usb_keyboard,
+            ctx.shared.usb_consumer,
+            ctx.shared.leds,
+        )
+            .lock(|pipeline, settings, mouse, keyboard, consumer, leds| {
+                let mut sink = HidSink {
+                    mouse,
+                    keyboard,
+                    consumer,
+                };
+                let now = now_ms();
+                if let Some(mode) = pipeline.button(button, pressed, now, settings, &mut sink) {
+                    leds.show(mode, settings);
+                }
+                schedule_gestures(pipeline, now);
+            });
     }
 
-    #[task(binds = EXTI4_15, local = [usr_led], shared = [exti, usb_hid, bbled_red, bbled_grn, bbled_wht, bbled_blu])]
-    fn exti_4_15_interrupt(mut ctx: exti_4_15_interrupt::Context) {
-        rprintln!("Interrupts happening on EXTI for PA15...");
+    /// Move drag-lock and double-click on, see `bbtrackball_pipeline::gesture`
+    #[task(shared = [pipeline, settings, usb_hid, usb_keyboard, usb_consumer])]
+    fn gesture_tick(ctx: gesture_tick::Context) {
+        (
+            ctx.shared.pipeline,
+            ctx.shared.settings,
+            ctx.shared.usb_hid,
+            ctx.shared.usb_keyboard,
+            ctx.shared.usb_consumer,
+        )
+            .lock(|pipeline, settings, mouse, keyboard, consumer| {
+                let now = now_ms();
+                pipeline.tick(
+                    now,
+                    settings,
+                    &mut HidSink {
+                        mouse,
+                        keyboard,
+                        consumer,
+                    },
+                );
+                schedule_gestures(pipeline, now);
+            });
+    }
 
-        match ctx.shared.exti.lock(|exti| exti.pr.read().bits()) {
-            0x8000 => {
-                rprintln!("PA15 triggered");
-                ctx.shared
-                    .exti
-                    .lock(|exti| exti.pr.write(|w| w.pif15().set_bit())); // Clear interrupt
+    /// Ask a suspended host to resume, see `power::wake_host`
+    fn wake_host() {
+        if power::wake_host()
+            && end_resume::spawn_after(Duration::<u64, 1, 1000>::from_ticks(power::RESUME_MS))
+                .is_err()
+        {
+            // Never leave the bus in resume signalling
+            power::end_resume();
+        }
+    }
 
-                ctx.shared
-                    .usb_hid
-                    .lock(|hid| super::send_mouse_report(Exclusive(hid), 0, 0, 1));
-                ctx.local.usr_led.toggle().ok();
-            }
-            0x10 => {
-                rprintln!("tb_left triggered!");
-                ctx.shared
-                    .exti
-                    .lock(|exti| exti.pr.write(|w| w.pif4().set_bit()));
-                ctx.shared
-                    .usb_hid
-                    .lock(|hid| super::send_mouse_report(Exclusive(hid), 5, 0, 0));
-                ctx.local.usr_led.toggle().ok();
-            }
-            0x20 => {
-                rprintln!("tb_up triggered!");
-                ctx.shared
-                    .exti
-                    .lock(|exti| exti.pr.write(|w| w.pif5().set_bit()));
+    /// Ends the resume signalling started by `wake_host`
+    #[task]
+    fn end_resume(_: end_resume::Context) {
+        power::end_resume();
+    }
 
-                ctx.shared
-                    .usb_hid
-                    .lock(|hid| super::send_mouse_report(Exclusive(hid), 0, 5, 0));
-                ctx.local.usr_led.toggle().ok();
-            }
-            0x40 => {
-                rprintln!("tb_right triggered!");
-                ctx.shared
-                    .exti
-                    .lock(|exti| exti.pr.write(|w| w.pif6().set_bit()));
+    /// Monotonic time in ms, wrapping around after 49 days like the pipeline expects
+    fn now_ms() -> u32 {
+        monotonics::now().ticks() as u32
+    }
 
-                ctx.shared
-                    .usb_hid
-                    .lock(|hid| super::send_mouse_report(Exclusive(hid), -5, 0, 0));
-                ctx.local.usr_led.toggle().ok();
-            }
-            0x80 => {
-                rprintln!("tb_down triggered!");
-                ctx.shared
-                    .exti
-                    .lock(|exti| exti.pr.write(|w| w.pif7().set_bit()));
+    /// Run `gesture_tick` at the pipeline's next deadline. A deadline in the past means a
+    /// report is held back by a busy endpoint, so try again shortly.
+    fn schedule_gestures(pipeline: &Pipeline, now: u32) {
+        if let Some(deadline) = pipeline.deadline() {
+            let wait = (deadline.wrapping_sub(now) as i32).max(1) as u64;
+            // Fails if a tick is already scheduled, which then reschedules itself
+            gesture_tick::spawn_after(Duration::<u64, 1, 1000>::from_ticks(wait)).ok();
+        }
+    }
+
+    /// Update the LEDs for a change in caps lock, host colour or bus suspend.
+    /// The LEDs are off while the bus is suspended, and back on when it resumes.
+    #[task(capacity = 4, shared = [pipeline, settings, leds])]
+    fn update_leds(ctx: update_leds::Context, change: LedChange) {
+        match change {
+            LedChange::Suspended(true) => log!("USB suspended"),
+            LedChange::Suspended(false) => log!("USB resumed"),
+            _ => {}
+        }
+        (ctx.shared.pipeline, ctx.shared.settings, ctx.shared.leds).lock(
+            |pipeline, settings, leds| {
+                leds.change(change);
+                leds.show(pipeline.mode(), settings);
+            },
+        );
+    }
+
+    /// Put settings written by the host into use
+    #[task(shared = [pipeline, settings, leds])]
+    fn apply_settings(ctx: apply_settings::Context, new_settings: Settings) {
+        log!("Applying new settings");
+        (ctx.shared.pipeline, ctx.shared.settings, ctx.shared.leds).lock(
+            |pipeline, settings, leds| {
+                if *settings != new_settings {
+                    *settings = new_settings;
+                    save_settings::spawn(new_settings).ok();
+                }
+                leds.show(pipeline.mode(), settings);
+            },
+        );
+    }
+
+    /// Write settings to flash. The program runs from the same flash, so a page erase stalls the
+    /// whole CPU, USB interrupt included, for up to 40 ms. The USB peripheral NAKs on its own
+    /// meanwhile and the host retries. Each EXTI line latches only one trackball pulse, so the
+    /// others that arrive during the erase are lost.
+    #[task(local = [store])]
+    fn save_settings(ctx: save_settings::Context, settings: Settings) {
+        match ctx.local.store.save(&settings) {
+            Ok(()) => log!("Saved settings to flash"),
+            Err(err) => log!("Saving settings failed: {:?}", err),
+        }
+    }
 
+    /// Answer a command line typed into the serial console
+    #[task(capacity = 2, shared = [counters, pipeline, button3, button5])]
+    fn console_command(mut ctx: console_command::Context, line: Line) {
+        match line.as_str() {
+            "help" => log!("commands: help, buttons, counters, reset"),
+            "buttons" => {
+                let button3 = ctx
+                    .shared
+                    .button3
+                    .lock(|button| button.is_low() == Ok(true));
+                let button5 = ctx
+                    .shared
+                    .button5
+                    .lock(|button| button.is_low() == Ok(true));
+                let (buttons, mode) = ctx
+                    .shared
+                    .pipeline
+                    .lock(|pipeline| (pipeline.mouse_buttons(), pipeline.mode()));
+                log!(
+                    "PA15 pressed: {}, PB3 pressed: {}, mouse buttons: {:#04x}, mode: {:?}",
+                    Plain::bool(button3),
+                    Plain::bool(button5),
+                    buttons,
+                    mode
+                );
+            }
+            "counters" => ctx.shared.counters.lock(|counters| {
+                log!(
+                    "tb_left: {}, tb_up: {}, tb_right: {}, tb_down: {}, aux presses: {}",
+                    counters.tb_left,
+                    counters.tb_up,
+                    counters.tb_right,
+                    counters.tb_down,
+                    counters.aux_presses
+                )
+            }),
+            "reset" => {
                 ctx.shared
-                    .usb_hid
-                    .lock(|hid| super::send_mouse_report(Exclusive(hid), 0, -5, 0));
-                ctx.local.usr_led.toggle().ok();
+                    .counters
+                    .lock(|counters| *counters = Counters::default());
+                log!("counters reset");
             }
-
-            _ => rprintln!("Some other bits were pushed around on EXTI4_15 ;)"),
+            other => log!("unknown command '{}', try 'help'", Plain(other)),
         }
     }
 
-    #[task(binds = USB, local = [usb_device], shared = [usb_hid])]
-    fn usb_handler(mut ctx: usb_handler::Context) {
-        rprintln!("USB interrupt received.");
+    #[task(binds = USB, local = [usb_device, usb_settings, usb_dfu, console_line: Line = Line::new(), usb_state: UsbDeviceState = UsbDeviceState::Default], shared = [pipeline, usb_hid, usb_keyboard, usb_consumer, usb_serial])]
+    fn usb_handler(ctx: usb_handler::Context) {
+        #[cfg(feature = "verbose-log")]
+        trace!("USB interrupt received.");
 
         let device = ctx.local.usb_device;
-        ctx.shared.usb_hid.lock(|hid| {
-            // USB dev poll only in the interrupt handler
-            device.poll(&mut [hid]);
-        });
-    }
-}
+        let settings = ctx.local.usb_settings;
+        let dfu = ctx.local.usb_dfu;
+        let line = ctx.local.console_line;
+        let usb_state = ctx.local.usb_state;
+        (
+            ctx.shared.pipeline,
+            ctx.shared.usb_hid,
+            ctx.shared.usb_keyboard,
+            ctx.shared.usb_consumer,
+            ctx.shared.usb_serial,
+        )
+            .lock(|pipeline, hid, keyboard, consumer, serial| {
+                // USB dev poll only in the interrupt handler
+                let polled = device.poll(&mut [hid, keyboard, consumer, serial, settings, dfu]);
+
+                // A bus reset puts the mouse back into report protocol. `poll`
+                // returns false for the reset itself, so watch the state.
+                let state = device.state();
+                if state != *usb_state && state == UsbDeviceState::Default {
+                    hid.set_protocol_mode(
+                        HidProtocolMode::Report,
+                        ProtocolModeConfig::DefaultBehavior,
+                    )
+                    .ok();
+                }
+                *usb_state = state;
+
+                if polled {
+                    // Keyboard LEDs, on the OUT endpoint or as a SET_REPORT
+                    let mut report = [0u8; 8];
+                    let keyboard_leds = match keyboard.pull_raw_output(&mut report) {
+                        Ok(len) if len > 0 => Some(report[0]),
+                        _ => keyboard
+                            .pull_raw_report(&mut report)
+                            .ok()
+                            .map(|_| report[0]),
+                    };
+                    if let Some(keyboard_leds) = keyboard_leds {
+                        update_leds::spawn(LedChange::CapsLock(keyboard_leds & CAPS_LOCK != 0))
+                            .ok();
+                    }
+
+                    let mut buf = [0u8; 16];
+                    if let Ok(count) = serial.read(&mut buf) {
+                        for &byte in &buf[..count] {
+                            if console::receive(line, byte) {
+                                console_command::spawn(line.clone()).ok();
+                                line.clear();
+                            }
+                        }
+                    }
+                }
+
+                // Movement collected while the mouse endpoint was busy
+                pipeline.flush(&mut HidSink {
+                    mouse: hid,
+                    keyboard,
+                    consumer,
+                });
+                console::flush(serial);
+            });
+
+        if let Some(new_settings) = settings.take_update() {
+            apply_settings::spawn(new_settings).ok();
+        }
+        if let Some(color) = settings.take_color() {
+            update_leds::spawn(LedChange::HostColor(color)).ok();
+        }
+        if dfu.take_detach() {
+            dfu::reboot_to_bootloader(SYSCLK_HZ);
+        }
 
-fn send_mouse_report(
-    mut shared_hid: impl Mutex<T = HIDClass<'static, usb::UsbBusType>>,
-    x: i8,
-    y: i8,
-    buttons: u8,
-) {
-    let mr = MouseReport {
-        x,
-        y,
-        buttons,
-        wheel: 0,
-        pan: 0,
-    };
-
-    shared_hid.lock(|hid| {
-        rprintln!("Sending mouse report...");
-        hid.push_input(&mr).ok();
-    });
+        let suspended = device.state() == UsbDeviceState::Suspend;
+        if power::update(suspended, device.remote_wakeup_enabled()) {
+            update_leds::spawn(LedChange::Suspended(suspended)).ok();
+        }
+    }
 }
@@ -0,0 +1,88 @@
+//! USB suspend/resume handling: stop mode while the bus is suspended, and remote wakeup of the host.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::interrupt::free as disable_interrupts;
+use cortex_m::peripheral::SCB;
+use stm32f0xx_hal::pac;
+
+/// Set while the host has suspended the bus
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// Set while the host allows us to wake it up
+static REMOTE_WAKEUP: AtomicBool = AtomicBool::new(false);
+/// Set while we signal resume to the host
+static RESUMING: AtomicBool = AtomicBool::new(false);
+
+/// Resume signalling must last between 1 and 15 ms, after which the host takes over
+pub const RESUME_MS: u64 = 5;
+
+/// Record the bus state after a USB poll. Returns true if the suspended state changed.
+pub fn update(suspended: bool, remote_wakeup_enabled: bool) -> bool {
+    REMOTE_WAKEUP.store(remote_wakeup_enabled, Ordering::Relaxed);
+
+    // usb_handler is the only writer, so no need for a (non-existent on thumbv6m) swap
+    let changed = SUSPENDED.load(Ordering::Relaxed) != suspended;
+    SUSPENDED.store(suspended, Ordering::Relaxed);
+    changed
+}
+
+/// Ask a suspended host to resume, if it enabled remote wakeup.
+/// Called on any button press or trackball movement. Returns true if resume signalling
+/// started, which the caller then ends with `end_resume` after `RESUME_MS`.
+pub fn wake_host() -> bool {
+    if !(SUSPENDED.load(Ordering::Relaxed) && REMOTE_WAKEUP.load(Ordering::Relaxed))
+        || RESUMING.load(Ordering::Relaxed)
+    {
+        return false;
+    }
+
+    // stm32-usbd has no API for this, so drive the RESUME bit directly.
+    // Safety: only touches CNTR bits that the USB driver leaves alone while suspended.
+    let usb = unsafe { &*pac::USB::ptr() };
+    usb.cntr
+        .modify(|_, w| w.lpmode().clear_bit().resume().set_bit());
+    RESUMING.store(true, Ordering::Relaxed);
+
+    // Only signal once, usb_handler will see the bus come back
+    REMOTE_WAKEUP.store(false, Ordering::Relaxed);
+    true
+}
+
+/// Stop the resume signalling started by `wake_host`.
+pub fn end_resume() {
+    // Safety: as in `wake_host`, the RESUME bit is ours alone.
+    let usb = unsafe { &*pac::USB::ptr() };
+    usb.cntr.modify(|_, w| w.resume().clear_bit());
+    RESUMING.store(false, Ordering::Relaxed);
+}
+
+/// Wait for the next interrupt. While the bus is suspended this uses stop mode,
+/// which also stops HSI48, so the system clock is restored before any task runs.
+pub fn wait(scb: &mut SCB) {
+    disable_interrupts(|_| {
+        // Stop mode would also stop the SysTick that times the end of resume signalling
+        if !SUSPENDED.load(Ordering::Relaxed) || RESUMING.load(Ordering::Relaxed) {
+            cortex_m::asm::wfi();
+            return;
+        }
+
+        // Safety: PWR is not used anywhere else. RCC is only touched here to restore the
+        // clock configuration that `init` set up, once stop mode has reset it to HSI.
+        let (pwr, rcc) = unsafe { (&*pac::PWR::ptr(), &*pac::RCC::ptr()) };
+
+        // Stop mode with the voltage regulator in low power. USB resume (EXTI line 18) and the
+        // trackball and aux button EXTI lines all wake us up again.
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr
+            .modify(|_, w| w.pdds().clear_bit().lpds().set_bit().cwuf().set_bit());
+        scb.set_sleepdeep();
+        cortex_m::asm::wfi();
+        scb.clear_sleepdeep();
+
+        // Back on HSI, switch the system clock back to HSI48
+        rcc.cr2.modify(|_, w| w.hsi48on().set_bit());
+        while rcc.cr2.read().hsi48rdy().bit_is_clear() {}
+        rcc.cfgr.modify(|_, w| w.sw().hsi48());
+        while !rcc.cfgr.read().sws().is_hsi48() {}
+    });
+}
@@ -0,0 +1,159 @@
+//! Vendor-defined HID interface carrying the settings feature report.
+//!
+//! usbd-hid rejects GET_REPORT, so this is a minimal HID class of its own which answers
+//! GET_REPORT and SET_REPORT for the feature report on the control pipe, and SET_REPORT for the
+//! LED colour output report.
+//! The report layout lives in the `bbtrackball-settings` crate, shared with the host tool.
+
+use bbtrackball_settings::{Color, Settings, REPORT_DESCRIPTOR, REPORT_LEN};
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_SET_IDLE: u8 = 0x0a;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REPORT_TYPE_OUTPUT: u8 = 0x02;
+const HID_REPORT_TYPE_FEATURE: u8 = 0x03;
+
+/// HID descriptor body: HID 1.10, no country code, one report descriptor
+const HID_DESCRIPTOR: [u8; 7] = [
+    0x10,
+    0x01,
+    0x00,
+    0x01,
+    HID_DESC_DESCTYPE_HID_REPORT,
+    REPORT_DESCRIPTOR.len() as u8,
+    0x00,
+];
+
+pub struct SettingsClass<'a, B: UsbBus> {
+    if_num: InterfaceNumber,
+    /// HID requires an interrupt IN endpoint, even though nothing is ever sent on it
+    in_ep: EndpointIn<'a, B>,
+    /// What GET_REPORT answers with
+    report: [u8; REPORT_LEN],
+    /// Settings from the last valid SET_REPORT, until picked up
+    update: Option<Settings>,
+    /// LED colour from the last valid output report, until picked up
+    color: Option<Option<Color>>,
+}
+
+impl<B: UsbBus> SettingsClass<'_, B> {
+    pub fn new<'a>(alloc: &'a UsbBusAllocator<B>, settings: &Settings) -> SettingsClass<'a, B> {
+        SettingsClass {
+            if_num: alloc.interface(),
+            in_ep: alloc.interrupt(8, 255),
+            report: settings.encode(),
+            update: None,
+            color: None,
+        }
+    }
+
+    /// Settings written by the host since the last call
+    pub fn take_update(&mut self) -> Option<Settings> {
+        self.update.take()
+    }
+
+    /// LED colour written by the host since the last call.
+    /// `Some(None)` means the host handed the LEDs back to the firmware.
+    pub fn take_color(&mut self) -> Option<Option<Color>> {
+        self.color.take()
+    }
+
+    /// Is this control request for our interface?
+    fn is_ours(&self, req: &control::Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == u8::from(self.if_num) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for SettingsClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.if_num, USB_CLASS_HID, 0x00, 0x00)?;
+        writer.write(HID_DESC_DESCTYPE_HID, &HID_DESCRIPTOR)?;
+        writer.endpoint(&self.in_ep)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_ours(&req) {
+            return;
+        }
+
+        match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match (req.value >> 8) as u8 {
+                HID_DESC_DESCTYPE_HID_REPORT => {
+                    xfer.accept_with_static(&REPORT_DESCRIPTOR).ok();
+                }
+                HID_DESC_DESCTYPE_HID => {
+                    let mut desc = [0; 9];
+                    desc[0] = desc.len() as u8;
+                    desc[1] = HID_DESC_DESCTYPE_HID;
+                    desc[2..].copy_from_slice(&HID_DESCRIPTOR);
+                    xfer.accept_with(&desc).ok();
+                }
+                _ => {}
+            },
+            (RequestType::Class, HID_REQ_GET_REPORT)
+                if (req.value >> 8) as u8 == HID_REPORT_TYPE_FEATURE =>
+            {
+                xfer.accept_with(&self.report).ok();
+            }
+            (RequestType::Class, _) => {
+                xfer.reject().ok();
+            }
+            _ => {}
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_ours(&req) {
+            return;
+        }
+
+        match (req.request_type, req.request) {
+            (RequestType::Class, HID_REQ_SET_IDLE) => {
+                xfer.accept().ok();
+            }
+            (RequestType::Class, HID_REQ_SET_REPORT)
+                if (req.value >> 8) as u8 == HID_REPORT_TYPE_FEATURE =>
+            {
+                // Invalid settings stall the request, so the host tool sees the error
+                match Settings::decode(xfer.data()) {
+                    Ok(settings) => {
+                        self.report = settings.encode();
+                        self.update = Some(settings);
+                        xfer.accept().ok();
+                    }
+                    Err(_) => {
+                        xfer.reject().ok();
+                    }
+                }
+            }
+            (RequestType::Class, HID_REQ_SET_REPORT)
+                if (req.value >> 8) as u8 == HID_REPORT_TYPE_OUTPUT =>
+            {
+                match Color::decode(xfer.data()) {
+                    Ok(color) => {
+                        self.color = Some(color);
+                        xfer.accept().ok();
+                    }
+                    Err(_) => {
+                        xfer.reject().ok();
+                    }
+                }
+            }
+            (RequestType::Class, _) => {
+                xfer.reject().ok();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Settings kept across power cycles in the last flash pages, which `memory.x` keeps the program out of.
+//!
+//! The record format and wear levelling live in `bbtrackball_settings::store`, this only does the
+//! flash erasing and programming.
+
+use bbtrackball_settings::store::{self, RECORD_LEN};
+use bbtrackball_settings::Settings;
+use stm32f0xx_hal::pac;
+
+/// Start of the SETTINGS region in `memory.x`
+const AREA_START: usize = 0x0800_7800;
+/// Flash page size of the STM32F042
+const PAGE_SIZE: usize = 1024;
+/// Length of the SETTINGS region in `memory.x`
+const AREA_LEN: usize = 2 * PAGE_SIZE;
+
+/// Flash unlock sequence, see RM0091 3.2.2
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+/// Why saving failed
+#[derive(Debug)]
+pub enum Error {
+    /// The flash controller flagged a programming or write protection error
+    Program,
+    /// The record did not read back correctly
+    Verify,
+}
+
+pub struct SettingsStore {
+    flash: pac::FLASH,
+}
+
+/// The storage area as it is in flash right now
+fn area() -> &'static [u8] {
+    // Safety: the area is reserved in memory.x, so it is always mapped and nothing else lives
+    // there. It is only modified by `SettingsStore::save`, which does not hold on to this slice.
+    unsafe { core::slice::from_raw_parts(AREA_START as *const u8, AREA_LEN) }
+}
+
+impl SettingsStore {
+    pub fn new(flash: pac::FLASH) -> Self {
+        SettingsStore { flash }
+    }
+
+    /// The last saved settings, or `None` if there are no valid ones
+    pub fn load(&self) -> Option<Settings> {
+        store::find_latest(area(), PAGE_SIZE).map(|latest| latest.settings)
+    }
+
+    /// Append the settings to the storage area, erasing a page first if needed.
+    /// The CPU stalls while a page is erased, which takes up to 40 ms.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), Error> {
+        let write = store::plan_write(area(), PAGE_SIZE);
+        let record = store::encode_record(write.seq, settings);
+
+        self.unlock();
+        let result = write
+            .erase
+            .map_or(Ok(()), |page| self.erase_page(AREA_START + page))
+            .and_then(|_| self.program(AREA_START + write.offset, &record));
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+        result?;
+
+        match store::decode_record(&area()[write.offset..]) {
+            Some((_, saved)) if saved == *settings => Ok(()),
+            _ => Err(Error::Verify),
+        }
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().lock().is_locked() {
+            self.flash.keyr.write(|w| w.fkeyr().bits(FLASH_KEY1));
+            self.flash.keyr.write(|w| w.fkeyr().bits(FLASH_KEY2));
+        }
+    }
+
+    /// Wait for the current operation to finish and check how it went
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+
+        let sr = self.flash.sr.read();
+        let failed = sr.pgerr().bit_is_set() || sr.wrprt().bit_is_set();
+        // Status flags are cleared by writing 1
+        self.flash
+            .sr
+            .write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+        if failed {
+            Err(Error::Program)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn erase_page(&mut self, address: usize) -> Result<(), Error> {
+        self.flash.cr.modify(|_, w| w.per().set_bit());
+        self.flash.ar.write(|w| w.far().bits(address as u32));
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.wait();
+        self.flash.cr.modify(|_, w| w.per().clear_bit());
+        result
+    }
+
+    fn program(&mut self, address: usize, data: &[u8; RECORD_LEN]) -> Result<(), Error> {
+        self.flash.cr.modify(|_, w| w.pg().set_bit());
+        let mut result = Ok(());
+        // Flash is programmed 16 bits at a time
+        for (i, halfword) in data.chunks(2).enumerate() {
+            let value = u16::from_le_bytes([halfword[0], halfword[1]]);
+            // Safety: within the storage area, which is unlocked and erased where we write
+            unsafe { core::ptr::write_volatile((address + 2 * i) as *mut u16, value) };
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        result
+    }
+}
@@ -0,0 +1,8 @@
+/* STM32F042G6, see the layout in ../stm32f0_hid_mouse_image/src/lib.rs */
+MEMORY
+{
+  /* The first flash page, the firmware image follows */
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 1K
+  /* The same RAM as the firmware, so the request word at the top survives until it is read */
+  RAM  (rwx) : ORIGIN = 0x200000C0, LENGTH = 6K - 0xC0 - 8
+}
@@ -0,0 +1,53 @@
+//! Bootloader of the STM32F0 trackball, in the first flash page.
+//!
+//! Starts the firmware if its image is intact, see `bbtrackball-image` for the layout and CRC.
+//! Otherwise, or when the firmware asked for it through the request word before resetting, it
+//! starts ST's USB DFU bootloader in system memory, which then takes the new image. A USB stack
+//! of our own would not fit in a page.
+//!
+//! No HAL: it only touches two registers, and every byte here is taken from the firmware.
+
+#![no_main]
+#![no_std]
+
+use core::ptr;
+
+use bbtrackball_image::{is_sealed, DFU_REQUEST, IMAGE_LEN, IMAGE_START, REQUEST};
+use cortex_m_rt::entry;
+use panic_halt as _;
+
+/// Start of the STM32F04x system memory, holding the DFU bootloader (AN2606)
+const SYSTEM_MEMORY: u32 = 0x1FFF_C400;
+
+/// RCC_APB2ENR, and its SYSCFG clock enable bit
+const RCC_APB2ENR: *mut u32 = 0x4002_1018 as *mut u32;
+const SYSCFGEN: u32 = 1 << 0;
+
+/// SYSCFG_CFGR1, and its MEM_MODE field choosing what is mapped at address 0
+const SYSCFG_CFGR1: *mut u32 = 0x4001_0000 as *mut u32;
+const MEM_MODE: u32 = 0b11;
+const MEM_MODE_SYSTEM: u32 = 0b01;
+
+#[entry]
+fn main() -> ! {
+    // Safety: the request word is reserved RAM, see `bbtrackball-image`. Cleared, so the next
+    // reset starts the firmware again.
+    let request = unsafe { ptr::read_volatile(REQUEST as *const u32) };
+    unsafe { ptr::write_volatile(REQUEST as *mut u32, 0) };
+
+    // Safety: flash is always readable
+    let image = unsafe { &*(IMAGE_START as *const [u8; IMAGE_LEN]) };
+    if request != DFU_REQUEST && is_sealed(image) {
+        // Safety: the image is intact, so it starts with the firmware's vector table
+        unsafe { cortex_m::asm::bootload(IMAGE_START as *const u32) }
+    }
+
+    // Safety: nothing else runs, and the system memory bootloader expects to find its vector
+    // table at address 0 like after a reset with BOOT0 high
+    unsafe {
+        ptr::write_volatile(RCC_APB2ENR, ptr::read_volatile(RCC_APB2ENR) | SYSCFGEN);
+        let cfgr1 = ptr::read_volatile(SYSCFG_CFGR1) & !MEM_MODE;
+        ptr::write_volatile(SYSCFG_CFGR1, cfgr1 | MEM_MODE_SYSTEM);
+        cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32)
+    }
+}
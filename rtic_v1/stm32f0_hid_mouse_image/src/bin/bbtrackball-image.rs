@@ -0,0 +1,54 @@
+//! Pads a raw trackball firmware binary to the full image length and appends its CRC, so the
+//! bootloader will start it.
+//!
+//! ```text
+//! cargo objcopy --release -- -O binary firmware.bin
+//! bbtrackball-image firmware.bin firmware.dfu.bin
+//! ```
+
+use std::env;
+use std::fs;
+use std::process;
+
+use bbtrackball_image::{is_sealed, seal, ERASED, IMAGE_LEN};
+
+const USAGE: &str = "usage: bbtrackball-image FIRMWARE.bin SEALED.bin";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (input, output) = match args {
+        [input, output] => (input, output),
+        _ => return Err(USAGE.into()),
+    };
+
+    let firmware = fs::read(input).map_err(|err| format!("{}: {}", input, err))?;
+    if firmware.len() > IMAGE_LEN - 4 {
+        return Err(format!(
+            "{}: {} bytes, only {} fit in front of the CRC",
+            input,
+            firmware.len(),
+            IMAGE_LEN - 4
+        ));
+    }
+
+    let mut image = [ERASED; IMAGE_LEN];
+    image[..firmware.len()].copy_from_slice(&firmware);
+    seal(&mut image);
+    debug_assert!(is_sealed(&image));
+
+    fs::write(output, &image[..]).map_err(|err| format!("{}: {}", output, err))?;
+    println!(
+        "{}: {} of {} bytes used",
+        output,
+        firmware.len(),
+        IMAGE_LEN - 4
+    );
+    Ok(())
+}
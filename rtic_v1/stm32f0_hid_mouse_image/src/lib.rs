@@ -0,0 +1,135 @@
+//! Flash and RAM layout shared by the STM32F0 trackball bootloader and firmware, and the CRC
+//! that lets the bootloader tell a complete firmware image from a broken one.
+//!
+//! Flash (32K, 1K pages):
+//!
+//! | Address     | Size | Contents                                                  |
+//! |-------------|------|-----------------------------------------------------------|
+//! | 0x0800_0000 | 1K   | Bootloader                                                |
+//! | 0x0800_0400 | 29K  | Firmware image, ending in the CRC-32 of everything before |
+//! | 0x0800_7800 | 2K   | Settings store, see `bbtrackball-settings`                |
+//!
+//! RAM (6K):
+//!
+//! | Address     | Size | Contents                                                  |
+//! |-------------|------|-----------------------------------------------------------|
+//! | 0x2000_0000 | 192  | Copy of the firmware's vector table, mapped at address 0  |
+//! | 0x2000_00C0 |      | Firmware data and stack                                   |
+//! | 0x2000_17F8 | 4    | Request to the bootloader, survives a reset               |
+//! | 0x2000_17FC | 4    | Unused, keeps the stack 8 byte aligned                    |
+//!
+//! The Cortex-M0 has no vector table offset register and always takes its vectors from address
+//! 0, where the bootloader is. So the firmware copies its vector table to the start of RAM and
+//! maps RAM at address 0 first thing.
+//!
+//! The image is always [`IMAGE_LEN`] bytes, padded with erased flash, so the CRC sits at a fixed
+//! place. [`seal`] writes it, the `bbtrackball-image` tool does so for a firmware binary.
+
+#![cfg_attr(not(test), no_std)]
+
+/// Start of the bootloader, and of flash
+pub const BOOTLOADER_START: u32 = 0x0800_0000;
+
+/// Start of the firmware image, right after the bootloader's page
+pub const IMAGE_START: u32 = 0x0800_0400;
+
+/// Length of the firmware image in bytes, including the CRC
+pub const IMAGE_LEN: usize = 29 * 1024;
+
+/// Length of the vector table in bytes: 16 exceptions and 32 interrupts
+pub const VECTOR_TABLE_LEN: usize = 48 * 4;
+
+/// Where the firmware's vector table is copied to
+pub const VECTOR_TABLE_COPY: u32 = 0x2000_0000;
+
+/// Address of the bootloader request word, near the top of RAM. Neither the bootloader nor the
+/// firmware use it for anything else, so it keeps its value across a reset.
+pub const REQUEST: u32 = 0x2000_17F8;
+
+/// Request value that makes the bootloader start the DFU bootloader in system memory instead
+/// of the firmware
+pub const DFU_REQUEST: u32 = 0x4446_5521;
+
+/// Value of erased flash, which pads the image up to the CRC
+pub const ERASED: u8 = 0xFF;
+
+/// CRC-32 lookup table for one nibble at a time, small enough for the bootloader
+const CRC_TABLE: [u32; 16] = crc_table();
+
+const fn crc_table() -> [u32; 16] {
+    let mut table = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 4 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (ISO-HDLC, as used by zlib and Ethernet): reflected polynomial 0xEDB88320,
+/// initial value and final XOR 0xFFFFFFFF
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        let crc = crc ^ byte as u32;
+        let crc = crc >> 4 ^ CRC_TABLE[(crc & 0xF) as usize];
+        crc >> 4 ^ CRC_TABLE[(crc & 0xF) as usize]
+    })
+}
+
+/// Write the CRC into the last 4 bytes of an image
+pub fn seal(image: &mut [u8; IMAGE_LEN]) {
+    let crc = crc32(&image[..IMAGE_LEN - 4]);
+    image[IMAGE_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Does an image end in the CRC of the rest of it
+pub fn is_sealed(image: &[u8; IMAGE_LEN]) -> bool {
+    let crc = &image[IMAGE_LEN - 4..];
+    crc == crc32(&image[..IMAGE_LEN - 4]).to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn seal_and_check() {
+        let mut image = [ERASED; IMAGE_LEN];
+        image[..4].copy_from_slice(&[0x00, 0x18, 0x00, 0x20]);
+        assert!(!is_sealed(&image));
+
+        seal(&mut image);
+        assert!(is_sealed(&image));
+
+        // A single flipped bit anywhere, e.g. an interrupted download, is caught
+        for offset in [0, 1000, IMAGE_LEN - 5, IMAGE_LEN - 1] {
+            let mut broken = image;
+            broken[offset] ^= 0x10;
+            assert!(!is_sealed(&broken));
+        }
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(IMAGE_START as usize, BOOTLOADER_START as usize + 1024);
+        // The settings store starts where the image ends
+        assert_eq!(IMAGE_START as usize + IMAGE_LEN, 0x0800_7800);
+        assert_eq!(REQUEST, 0x2000_0000 + 6 * 1024 - 8);
+    }
+}
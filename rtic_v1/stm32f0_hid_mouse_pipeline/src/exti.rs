@@ -0,0 +1,66 @@
+//! Which inputs a value of the EXTI pending register stands for.
+//!
+//! | EXTI line | Pin  | Input            |
+//! |-----------|------|------------------|
+//! | 3         | PB3  | Aux button 5     |
+//! | 4         | PA4  | Trackball left   |
+//! | 5         | PA5  | Trackball up     |
+//! | 6         | PA6  | Trackball right  |
+//! | 7         | PA7  | Trackball down   |
+//! | 15        | PA15 | Aux button 3     |
+
+use crate::{AuxButton, Direction};
+
+/// An input pin with an interrupt pending
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Input {
+    Trackball(Direction),
+    Button(AuxButton),
+}
+
+/// Lines served by the EXTI2_3 interrupt
+pub const EXTI2_3: u32 = 0x0000_000C;
+/// Lines served by the EXTI4_15 interrupt
+pub const EXTI4_15: u32 = 0x0000_FFF0;
+
+const LINES: [(u32, Input); 6] = [
+    (3, Input::Button(AuxButton::Button5)),
+    (4, Input::Trackball(Direction::Left)),
+    (5, Input::Trackball(Direction::Up)),
+    (6, Input::Trackball(Direction::Right)),
+    (7, Input::Trackball(Direction::Down)),
+    (15, Input::Button(AuxButton::Button3)),
+];
+
+/// The inputs pending in `pr`, lowest line first, each with the bit that clears it.
+/// Lines without an input are skipped, they are never unmasked.
+pub fn pending(pr: u32) -> impl Iterator<Item = (u32, Input)> {
+    LINES
+        .iter()
+        .map(|&(line, input)| (1 << line, input))
+        .filter(move |&(bit, _)| pr & bit != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_lines() {
+        assert_eq!(pending(0).count(), 0);
+        assert_eq!(
+            pending(0x8000).collect::<Vec<_>>(),
+            [(0x8000, Input::Button(AuxButton::Button3))]
+        );
+        // Two trackball pulses at once are both handled, the old exact match dropped them
+        assert_eq!(
+            pending(0x0050 | 0x0002).collect::<Vec<_>>(),
+            [
+                (0x10, Input::Trackball(Direction::Left)),
+                (0x40, Input::Trackball(Direction::Right))
+            ]
+        );
+        assert_eq!(pending(EXTI2_3).count(), 1);
+        assert_eq!(pending(EXTI4_15).count(), 5);
+    }
+}
@@ -0,0 +1,154 @@
+//! Aux button gestures that depend on timing: drag-lock and double-click.
+//!
+//! The gesture engine sits between the button edges and the mouse report. It works on
+//! timestamps in milliseconds, which wrap around, taken from the firmware's monotonic timer or
+//! made up by the tests. Whenever [`Gestures::deadline`] is `Some`, the caller has to call
+//! [`Gestures::tick`] again once that time has come.
+
+use bbtrackball_settings::{Settings, BUTTONS};
+
+use crate::AuxButton;
+
+/// Left mouse button bit in `MouseReport::buttons`, the one both gestures click
+const LEFT: u8 = 0x01;
+
+/// Drag-lock state of one aux button
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Drag {
+    Idle,
+    /// Pressed at this time, not known yet whether it is a tap
+    Held {
+        since: u32,
+    },
+    /// Tapped, left stays down until the next press and release
+    Locked,
+    /// Pressed again while locked, lets go on release
+    Unlocking,
+}
+
+/// Timed gestures of the aux buttons, and the mouse buttons they hold down
+pub struct Gestures {
+    drag: [Drag; BUTTONS],
+    /// Left button edges of a double-click still to come. Left is down while this is odd.
+    clicks: u8,
+    /// When the next double-click edge is due
+    due: u32,
+}
+
+impl Default for Gestures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Has `now` reached `time`, allowing for the timestamps wrapping around
+fn reached(now: u32, time: u32) -> bool {
+    now.wrapping_sub(time) as i32 >= 0
+}
+
+impl Gestures {
+    pub const fn new() -> Self {
+        Gestures {
+            drag: [Drag::Idle; BUTTONS],
+            clicks: 0,
+            due: 0,
+        }
+    }
+
+    /// Mouse buttons currently held down by gestures
+    pub fn buttons(&self) -> u8 {
+        let dragging = self.drag.iter().any(|&drag| drag != Drag::Idle);
+        if dragging || self.clicks % 2 == 1 {
+            LEFT
+        } else {
+            0
+        }
+    }
+
+    /// When [`Gestures::tick`] has to be called next, if at all
+    pub fn deadline(&self) -> Option<u32> {
+        (self.clicks > 0).then_some(self.due)
+    }
+
+    /// An edge of a button bound to `Action::DragLock`.
+    /// Returns whether the held mouse buttons changed.
+    pub fn drag_lock(
+        &mut self,
+        button: AuxButton,
+        pressed: bool,
+        now: u32,
+        settings: &Settings,
+    ) -> bool {
+        let before = self.buttons();
+        let drag = &mut self.drag[button as usize];
+        *drag = match (*drag, pressed) {
+            (Drag::Idle, true) => Drag::Held { since: now },
+            (Drag::Held { since }, false) => {
+                if now.wrapping_sub(since) < u32::from(settings.tap_ms) {
+                    Drag::Locked
+                } else {
+                    Drag::Idle
+                }
+            }
+            (Drag::Locked, true) => Drag::Unlocking,
+            (Drag::Unlocking, false) => Drag::Idle,
+            // A missed edge, e.g. a bounce. Stay put.
+            (drag, _) => drag,
+        };
+        self.buttons() != before
+    }
+
+    /// Let go of a drag when its button was bound to something else.
+    /// Returns whether the held mouse buttons changed.
+    pub fn cancel_drag(&mut self, button: AuxButton) -> bool {
+        let before = self.buttons();
+        self.drag[button as usize] = Drag::Idle;
+        self.buttons() != before
+    }
+
+    /// A press of a button bound to `Action::DoubleClick`. Starts the double-click unless one
+    /// is already under way. Returns whether the held mouse buttons changed.
+    pub fn double_click(&mut self, now: u32, settings: &Settings) -> bool {
+        if self.clicks > 0 {
+            return false;
+        }
+        let before = self.buttons();
+        self.clicks = 3;
+        self.due = now.wrapping_add(u32::from(settings.click_ms));
+        self.buttons() != before
+    }
+
+    /// Give the current double-click edge a full step from `now`, as its report only just made
+    /// it out
+    pub fn postpone(&mut self, now: u32, settings: &Settings) {
+        self.due = now.wrapping_add(u32::from(settings.click_ms));
+    }
+
+    /// Move the double-click on to its next edge if it is due.
+    /// Returns whether the held mouse buttons changed.
+    pub fn tick(&mut self, now: u32, settings: &Settings) -> bool {
+        match self.deadline() {
+            Some(due) if reached(now, due) => {
+                let before = self.buttons();
+                self.clicks -= 1;
+                // Counted from now rather than from `due`, so a late tick still leaves the
+                // host a full step to see each edge
+                self.due = now.wrapping_add(u32::from(settings.click_ms));
+                self.buttons() != before
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_wraps_around() {
+        assert!(reached(5, u32::MAX - 5));
+        assert!(!reached(u32::MAX - 5, 5));
+        assert!(reached(7, 7));
+    }
+}
@@ -0,0 +1,251 @@
+//! The trackball firmware's path from input events to HID reports, kept apart from the RTIC
+//! tasks and USB classes so it can be tested on the host.
+//!
+//! The firmware decodes EXTI interrupts with [`exti::pending`], feeds the resulting trackball
+//! pulses and aux button edges to a [`Pipeline`], and hands it the HID interfaces as a
+//! [`ReportSink`]. Tests hand it a sink that records the reports instead.
+//!
+//! Drag-lock and double-click need time to pass between reports, so the pipeline also takes
+//! timestamps and wants [`Pipeline::tick`] called at its [`Pipeline::deadline`], see
+//! [`gesture`].
+
+#![cfg_attr(not(test), no_std)]
+
+use bbtrackball_settings::{Action, Settings};
+pub use usbd_hid::descriptor::MouseReport;
+
+pub mod exti;
+pub mod gesture;
+
+use gesture::Gestures;
+
+/// What the trackball pulses are turned into, toggled by an aux button
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrackballMode {
+    /// Pulses move the pointer
+    Pointer,
+    /// Vertical pulses scroll the wheel, horizontal pulses pan
+    Scroll,
+}
+
+/// Direction of a trackball pulse, named after the sensor it comes from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Left,
+    Up,
+    Right,
+    Down,
+}
+
+impl Direction {
+    /// Movement of one pulse, in the report's axes
+    fn step(self) -> (i8, i8) {
+        match self {
+            Direction::Left => (1, 0),
+            Direction::Up => (0, 1),
+            Direction::Right => (-1, 0),
+            Direction::Down => (0, -1),
+        }
+    }
+}
+
+/// The aux buttons that can raise an interrupt.
+/// PB4 shares EXTI line 4 with the trackball's left sensor, so it cannot be mapped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuxButton {
+    /// PA15
+    Button3 = 0,
+    /// PB3
+    Button5 = 1,
+}
+
+impl AuxButton {
+    /// Look up the action currently bound to this button
+    pub fn action(self, settings: &Settings) -> Action {
+        settings.buttons[self as usize]
+    }
+}
+
+/// The endpoint has not sent the previous report yet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Busy;
+
+/// Where the reports go: the HID interfaces in the firmware
+pub trait ReportSink {
+    fn mouse(&mut self, report: &MouseReport) -> Result<(), Busy>;
+    fn keyboard(&mut self, modifier: u8, keycode: u8) -> Result<(), Busy>;
+    fn consumer(&mut self, usage_id: u16) -> Result<(), Busy>;
+}
+
+/// Mouse state, and movement that has not made it into a report yet
+pub struct Pipeline {
+    mode: TrackballMode,
+    /// Mouse buttons currently held down through aux buttons
+    buttons: u8,
+    gestures: Gestures,
+    x: i8,
+    y: i8,
+    wheel: i8,
+    pan: i8,
+    /// A mouse report is waiting for the endpoint
+    pending: bool,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub const fn new() -> Self {
+        Pipeline {
+            mode: TrackballMode::Pointer,
+            buttons: 0,
+            gestures: Gestures::new(),
+            x: 0,
+            y: 0,
+            wheel: 0,
+            pan: 0,
+            pending: false,
+        }
+    }
+
+    pub fn mode(&self) -> TrackballMode {
+        self.mode
+    }
+
+    /// Mouse buttons currently held down through aux buttons, directly or by gestures
+    pub fn mouse_buttons(&self) -> u8 {
+        self.buttons | self.gestures.buttons()
+    }
+
+    /// When [`Pipeline::tick`] has to be called next, if at all
+    pub fn deadline(&self) -> Option<u32> {
+        self.gestures.deadline()
+    }
+
+    /// Turn a trackball pulse into movement, according to the current mode
+    pub fn trackball(
+        &mut self,
+        direction: Direction,
+        settings: &Settings,
+        sink: &mut impl ReportSink,
+    ) {
+        let (dx, dy) = direction.step();
+        let pointer = settings.pointer_sensitivity as i8;
+        let scroll = settings.scroll_sensitivity as i8;
+        match self.mode {
+            TrackballMode::Pointer => {
+                self.x = self.x.saturating_add(dx * pointer);
+                self.y = self.y.saturating_add(dy * pointer);
+            }
+            // Wheel is positive upwards while y is positive downwards
+            TrackballMode::Scroll => {
+                self.wheel = self.wheel.saturating_add(-dy * scroll);
+                self.pan = self.pan.saturating_add(dx * scroll);
+            }
+        }
+        self.pending = true;
+        self.flush(sink);
+    }
+
+    /// Carry out the action bound to an aux button on a press or release at time `now` in ms.
+    /// Returns the new mode if the button changed it.
+    pub fn button(
+        &mut self,
+        button: AuxButton,
+        pressed: bool,
+        now: u32,
+        settings: &Settings,
+        sink: &mut impl ReportSink,
+    ) -> Option<TrackballMode> {
+        let action = button.action(settings);
+        // The button may have been remapped while locked, which would leave left stuck down
+        if action != Action::DragLock && self.gestures.cancel_drag(button) {
+            self.pending = true;
+        }
+
+        match action {
+            Action::None => {}
+            Action::Mouse(bits) => {
+                if pressed {
+                    self.buttons |= bits;
+                } else {
+                    self.buttons &= !bits;
+                }
+                self.pending = true;
+            }
+            // Keyboard and consumer reports are not retried, they do not queue up like movement
+            Action::Key { modifier, keycode } => {
+                let (modifier, keycode) = if pressed { (modifier, keycode) } else { (0, 0) };
+                sink.keyboard(modifier, keycode).ok();
+            }
+            Action::Consumer(usage_id) => {
+                sink.consumer(if pressed { usage_id } else { 0 }).ok();
+            }
+            Action::ToggleScroll if pressed => {
+                self.mode = match self.mode {
+                    TrackballMode::Pointer => TrackballMode::Scroll,
+                    TrackballMode::Scroll => TrackballMode::Pointer,
+                };
+                return Some(self.mode);
+            }
+            Action::ToggleScroll => {}
+            Action::DragLock => {
+                if self.gestures.drag_lock(button, pressed, now, settings) {
+                    self.pending = true;
+                }
+            }
+            Action::DoubleClick if pressed => {
+                if self.gestures.double_click(now, settings) {
+                    self.pending = true;
+                }
+            }
+            Action::DoubleClick => {}
+        }
+        self.flush(sink);
+        None
+    }
+
+    /// Move timed gestures on, at or after [`Pipeline::deadline`].
+    /// Each edge of a gesture needs a report of its own, so while the previous one is still
+    /// waiting for the endpoint nothing moves on and the deadline stays in the past. Once it
+    /// is sent, the next edge follows a full step later.
+    pub fn tick(&mut self, now: u32, settings: &Settings, sink: &mut impl ReportSink) {
+        if self.pending {
+            self.flush(sink);
+            if !self.pending {
+                self.gestures.postpone(now, settings);
+            }
+            return;
+        }
+        if self.gestures.tick(now, settings) {
+            self.pending = true;
+            self.flush(sink);
+        }
+    }
+
+    /// Send the pending mouse report, if any. Movement collected while the endpoint was busy
+    /// goes out in one report. Call again once the endpoint is free.
+    pub fn flush(&mut self, sink: &mut impl ReportSink) {
+        if !self.pending {
+            return;
+        }
+
+        let report = MouseReport {
+            buttons: self.mouse_buttons(),
+            x: self.x,
+            y: self.y,
+            wheel: self.wheel,
+            pan: self.pan,
+        };
+        if sink.mouse(&report).is_ok() {
+            self.x = 0;
+            self.y = 0;
+            self.wheel = 0;
+            self.pan = 0;
+            self.pending = false;
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! Feeds synthetic pin events through the pipeline, the way the firmware's interrupt handlers
+//! do, and checks the exact reports that come out.
+
+use bbtrackball_pipeline::exti::{self, Input};
+use bbtrackball_pipeline::{AuxButton, Busy, MouseReport, Pipeline, ReportSink, TrackballMode};
+use bbtrackball_settings::{Action, LedMode, Settings};
+
+/// A report as a comparable tuple: buttons, x, y, wheel, pan
+type Mouse = (u8, i8, i8, i8, i8);
+
+#[derive(Debug, PartialEq)]
+enum Report {
+    Mouse(Mouse),
+    Keyboard(u8, u8),
+    Consumer(u16),
+}
+
+/// Records reports, and can pretend the mouse endpoint is busy
+#[derive(Default)]
+struct Recorder {
+    reports: Vec<Report>,
+    busy: bool,
+}
+
+impl ReportSink for Recorder {
+    fn mouse(&mut self, report: &MouseReport) -> Result<(), Busy> {
+        if self.busy {
+            return Err(Busy);
+        }
+        self.reports.push(Report::Mouse((
+            report.buttons,
+            report.x,
+            report.y,
+            report.wheel,
+            report.pan,
+        )));
+        Ok(())
+    }
+
+    fn keyboard(&mut self, modifier: u8, keycode: u8) -> Result<(), Busy> {
+        self.reports.push(Report::Keyboard(modifier, keycode));
+        Ok(())
+    }
+
+    fn consumer(&mut self, usage_id: u16) -> Result<(), Busy> {
+        self.reports.push(Report::Consumer(usage_id));
+        Ok(())
+    }
+}
+
+/// The firmware with its HID interfaces swapped for a recorder
+struct Trackball {
+    pipeline: Pipeline,
+    settings: Settings,
+    usb: Recorder,
+    /// Levels of the aux button pins, true is pressed (low)
+    button3: bool,
+    button5: bool,
+    /// Monotonic time in ms
+    now: u32,
+}
+
+impl Trackball {
+    fn new(settings: Settings) -> Self {
+        Trackball {
+            pipeline: Pipeline::new(),
+            settings,
+            usb: Recorder::default(),
+            button3: false,
+            button5: false,
+            now: 0,
+        }
+    }
+
+    /// An EXTI interrupt with `pr` pending, as the interrupt handlers see it
+    fn interrupt(&mut self, pr: u32) {
+        for (_, input) in exti::pending(pr) {
+            match input {
+                Input::Trackball(direction) => {
+                    self.pipeline
+                        .trackball(direction, &self.settings, &mut self.usb)
+                }
+                Input::Button(button) => {
+                    let pressed = match button {
+                        AuxButton::Button3 => self.button3,
+                        AuxButton::Button5 => self.button5,
+                    };
+                    self.pipeline
+                        .button(button, pressed, self.now, &self.settings, &mut self.usb);
+                }
+            }
+        }
+    }
+
+    fn press3(&mut self, pressed: bool) {
+        self.button3 = pressed;
+        self.interrupt(1 << 15);
+    }
+
+    fn press5(&mut self, pressed: bool) {
+        self.button5 = pressed;
+        self.interrupt(1 << 3);
+    }
+
+    /// Let `ms` pass, ticking the pipeline at its deadlines like the firmware's timer task
+    fn wait(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.now = self.now.wrapping_add(1);
+            if self.pipeline.deadline() == Some(self.now) {
+                self.pipeline.tick(self.now, &self.settings, &mut self.usb);
+            }
+        }
+    }
+
+    fn take(&mut self) -> Vec<Report> {
+        std::mem::take(&mut self.usb.reports)
+    }
+}
+
+const LEFT: u32 = 1 << 4;
+const UP: u32 = 1 << 5;
+const RIGHT: u32 = 1 << 6;
+const DOWN: u32 = 1 << 7;
+
+fn settings() -> Settings {
+    Settings {
+        pointer_sensitivity: 5,
+        scroll_sensitivity: 1,
+        led_mode: LedMode::Mode,
+        buttons: [Action::Mouse(0x01), Action::ToggleScroll],
+        tap_ms: 200,
+        click_ms: 70,
+    }
+}
+
+#[test]
+fn pointer_movement() {
+    let mut trackball = Trackball::new(settings());
+    for pr in [LEFT, UP, RIGHT, DOWN] {
+        trackball.interrupt(pr);
+    }
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((0, 5, 0, 0, 0)),
+            Report::Mouse((0, 0, 5, 0, 0)),
+            Report::Mouse((0, -5, 0, 0, 0)),
+            Report::Mouse((0, 0, -5, 0, 0)),
+        ]
+    );
+}
+
+#[test]
+fn scroll_mode() {
+    let mut trackball = Trackball::new(settings());
+    trackball.press5(true);
+    trackball.press5(false);
+    assert_eq!(trackball.pipeline.mode(), TrackballMode::Scroll);
+    assert_eq!(trackball.take(), []);
+
+    for pr in [LEFT, UP, RIGHT, DOWN] {
+        trackball.interrupt(pr);
+    }
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((0, 0, 0, 0, 1)),
+            Report::Mouse((0, 0, 0, -1, 0)),
+            Report::Mouse((0, 0, 0, 0, -1)),
+            Report::Mouse((0, 0, 0, 1, 0)),
+        ]
+    );
+
+    trackball.press5(true);
+    assert_eq!(trackball.pipeline.mode(), TrackballMode::Pointer);
+}
+
+#[test]
+fn drag_with_aux_button() {
+    let mut trackball = Trackball::new(settings());
+    trackball.press3(true);
+    trackball.interrupt(LEFT);
+    trackball.interrupt(UP);
+    trackball.press3(false);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((1, 0, 0, 0, 0)),
+            Report::Mouse((1, 5, 0, 0, 0)),
+            Report::Mouse((1, 0, 5, 0, 0)),
+            Report::Mouse((0, 0, 0, 0, 0)),
+        ]
+    );
+}
+
+#[test]
+fn simultaneous_pulses() {
+    let mut trackball = Trackball::new(settings());
+    trackball.interrupt(LEFT | UP);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((0, 5, 0, 0, 0)),
+            Report::Mouse((0, 0, 5, 0, 0))
+        ]
+    );
+}
+
+#[test]
+fn accumulates_while_busy() {
+    let mut trackball = Trackball::new(settings());
+    trackball.usb.busy = true;
+    for _ in 0..3 {
+        trackball.interrupt(LEFT);
+    }
+    trackball.interrupt(UP);
+    trackball.press3(true);
+    assert_eq!(trackball.take(), []);
+
+    trackball.usb.busy = false;
+    trackball.pipeline.flush(&mut trackball.usb);
+    trackball.pipeline.flush(&mut trackball.usb);
+    assert_eq!(trackball.take(), [Report::Mouse((1, 15, 5, 0, 0))]);
+
+    // Saturates rather than wrapping around
+    trackball.usb.busy = true;
+    for _ in 0..100 {
+        trackball.interrupt(RIGHT);
+    }
+    trackball.usb.busy = false;
+    trackball.pipeline.flush(&mut trackball.usb);
+    assert_eq!(trackball.take(), [Report::Mouse((1, -128, 0, 0, 0))]);
+}
+
+#[test]
+fn key_and_consumer_actions() {
+    let mut settings = settings();
+    settings.buttons = [
+        Action::Key {
+            modifier: 0x01,
+            keycode: 0x06,
+        },
+        Action::Consumer(0xCD),
+    ];
+    let mut trackball = Trackball::new(settings);
+    trackball.press3(true);
+    trackball.press3(false);
+    trackball.press5(true);
+    trackball.press5(false);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Keyboard(0x01, 0x06),
+            Report::Keyboard(0, 0),
+            Report::Consumer(0xCD),
+            Report::Consumer(0),
+        ]
+    );
+}
+
+#[test]
+fn unbound_button() {
+    let mut settings = settings();
+    settings.buttons[0] = Action::None;
+    let mut trackball = Trackball::new(settings);
+    trackball.press3(true);
+    trackball.press3(false);
+    assert_eq!(trackball.take(), []);
+}
+
+#[test]
+fn drag_lock() {
+    let mut settings = settings();
+    settings.buttons[0] = Action::DragLock;
+    let mut trackball = Trackball::new(settings);
+
+    // A tap locks left down, moving drags
+    trackball.press3(true);
+    trackball.wait(199);
+    trackball.press3(false);
+    trackball.interrupt(LEFT);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((1, 0, 0, 0, 0)),
+            Report::Mouse((1, 5, 0, 0, 0))
+        ]
+    );
+
+    // The next press keeps holding, its release lets go
+    trackball.wait(1000);
+    trackball.press3(true);
+    trackball.interrupt(UP);
+    trackball.press3(false);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((1, 0, 5, 0, 0)),
+            Report::Mouse((0, 0, 0, 0, 0))
+        ]
+    );
+
+    // Held past the tap time it is an ordinary button
+    trackball.press3(true);
+    trackball.wait(200);
+    trackball.press3(false);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((1, 0, 0, 0, 0)),
+            Report::Mouse((0, 0, 0, 0, 0))
+        ]
+    );
+}
+
+#[test]
+fn drag_lock_across_timer_wrap() {
+    let mut settings = settings();
+    settings.buttons[0] = Action::DragLock;
+    let mut trackball = Trackball::new(settings);
+    trackball.now = u32::MAX - 50;
+    trackball.press3(true);
+    trackball.wait(100);
+    trackball.press3(false);
+    assert_eq!(trackball.pipeline.mouse_buttons(), 0x01);
+}
+
+#[test]
+fn remapping_releases_drag_lock() {
+    let mut settings = settings();
+    settings.buttons[0] = Action::DragLock;
+    let mut trackball = Trackball::new(settings);
+    trackball.press3(true);
+    trackball.press3(false);
+    assert_eq!(trackball.take(), [Report::Mouse((1, 0, 0, 0, 0))]);
+
+    trackball.settings.buttons[0] = Action::None;
+    trackball.press3(true);
+    assert_eq!(trackball.take(), [Report::Mouse((0, 0, 0, 0, 0))]);
+}
+
+#[test]
+fn double_click() {
+    let mut settings = settings();
+    settings.buttons[1] = Action::DoubleClick;
+    let mut trackball = Trackball::new(settings);
+    trackball.press5(true);
+    trackball.press5(false);
+    assert_eq!(trackball.take(), [Report::Mouse((1, 0, 0, 0, 0))]);
+
+    // Presses while it is under way are ignored
+    trackball.wait(69);
+    trackball.press5(true);
+    trackball.press5(false);
+    assert_eq!(trackball.take(), []);
+
+    trackball.wait(1);
+    assert_eq!(trackball.take(), [Report::Mouse((0, 0, 0, 0, 0))]);
+    trackball.wait(140);
+    assert_eq!(
+        trackball.take(),
+        [
+            Report::Mouse((1, 0, 0, 0, 0)),
+            Report::Mouse((0, 0, 0, 0, 0))
+        ]
+    );
+    assert_eq!(trackball.pipeline.deadline(), None);
+}
+
+#[test]
+fn double_click_waits_for_endpoint() {
+    let mut settings = settings();
+    settings.buttons[1] = Action::DoubleClick;
+    let mut trackball = Trackball::new(settings);
+    trackball.usb.busy = true;
+    trackball.press5(true);
+    trackball.wait(500);
+    assert_eq!(trackball.take(), []);
+
+    // Each edge still gets its own report, a full step apart
+    trackball.usb.busy = false;
+    let mut reports = Vec::new();
+    for _ in 0..4 {
+        let now = trackball.now;
+        trackball
+            .pipeline
+            .tick(now, &trackball.settings, &mut trackball.usb);
+        reports.push((trackball.now, trackball.take()));
+        trackball.wait(70);
+    }
+    assert_eq!(
+        reports,
+        [
+            (500, vec![Report::Mouse((1, 0, 0, 0, 0))]),
+            (570, vec![Report::Mouse((0, 0, 0, 0, 0))]),
+            (640, vec![Report::Mouse((1, 0, 0, 0, 0))]),
+            (710, vec![Report::Mouse((0, 0, 0, 0, 0))]),
+        ]
+    );
+}
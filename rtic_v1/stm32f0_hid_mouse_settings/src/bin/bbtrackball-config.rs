@@ -0,0 +1,215 @@
+//! Reads and writes the runtime settings of the STM32F0 trackball over its HID feature report,
+//! and sets the LED colour over its output report.
+//!
+//! ```text
+//! bbtrackball-config get
+//! bbtrackball-config set pointer=8 scroll=2 led=off button3=key:0x01:0x06 button5=scroll
+//! bbtrackball-config set button3=draglock button5=doubleclick tap=250 click=80
+//! bbtrackball-config color 255,0,64,0
+//! bbtrackball-config color auto
+//! ```
+
+use std::env;
+use std::process;
+
+use bbtrackball_settings::{
+    Action, Color, LedMode, Settings, OUTPUT_REPORT_LEN, REPORT_LEN, USAGE_PAGE,
+};
+use hidapi::{HidApi, HidDevice};
+
+/// Default USB identity of the trackball firmware, overridden by the same `BBTRACKBALL_VID` and
+/// `BBTRACKBALL_PID` environment variables as the firmware build
+const VID: u16 = 0x0000;
+const PID: u16 = 0x3821;
+
+const USAGE: &str = "usage: bbtrackball-config get
+       bbtrackball-config set [pointer=N] [scroll=N] [led=mode|off] [button3=ACTION] [button5=ACTION]
+                              [tap=MS] [click=MS]
+       bbtrackball-config color RED,GREEN,BLUE,WHITE|auto
+
+ACTION is one of: none, mouse:BITS, key:MODIFIER:KEYCODE, consumer:USAGE, scroll, draglock,
+doubleclick
+tap is the longest press that locks a draglock button, click the length of each half of a
+doubleclick. 0 picks the firmware default.
+Numbers are decimal or 0x prefixed hex.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, changes) = match args.split_first() {
+        Some((command, changes)) => (command.as_str(), changes),
+        None => return Err(USAGE.into()),
+    };
+
+    let device = open()?;
+    if command == "color" {
+        return match changes {
+            [color] => write_color(&device, color),
+            _ => Err(USAGE.into()),
+        };
+    }
+
+    let mut settings = read(&device)?;
+
+    match command {
+        "get" if changes.is_empty() => {}
+        "set" if !changes.is_empty() => {
+            for change in changes {
+                apply(&mut settings, change)?;
+            }
+            write(&device, &settings)?;
+            // Read back what the firmware actually accepted
+            settings = read(&device)?;
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    print(&settings);
+    Ok(())
+}
+
+/// Open the vendor-defined settings interface of the first trackball found
+fn open() -> Result<HidDevice, String> {
+    let vid = id("BBTRACKBALL_VID", VID)?;
+    let pid = id("BBTRACKBALL_PID", PID)?;
+    let api = HidApi::new().map_err(|err| err.to_string())?;
+    let info = api
+        .device_list()
+        .find(|info| {
+            info.vendor_id() == vid && info.product_id() == pid && info.usage_page() == USAGE_PAGE
+        })
+        .ok_or_else(|| format!("no trackball found ({:04x}:{:04x})", vid, pid))?;
+    info.open_device(&api).map_err(|err| err.to_string())
+}
+
+fn read(device: &HidDevice) -> Result<Settings, String> {
+    // The first byte is the report ID, always 0 as the descriptor does not use them
+    let mut report = [0; REPORT_LEN + 1];
+    let len = device
+        .get_feature_report(&mut report)
+        .map_err(|err| err.to_string())?;
+    Settings::decode(&report[1..len]).map_err(|err| format!("bad settings report: {:?}", err))
+}
+
+fn write(device: &HidDevice, settings: &Settings) -> Result<(), String> {
+    let mut report = [0; REPORT_LEN + 1];
+    report[1..].copy_from_slice(&settings.encode());
+    device
+        .send_feature_report(&report)
+        .map_err(|err| err.to_string())
+}
+
+/// Override the LED colour, or hand the LEDs back to the firmware with `auto`
+fn write_color(device: &HidDevice, color: &str) -> Result<(), String> {
+    let color = match color {
+        "auto" => Color::OFF,
+        _ => match color
+            .split(',')
+            .map(number)
+            .collect::<Result<Vec<u8>, _>>()?[..]
+        {
+            [red, green, blue, white] => Color::new(red, green, blue, white),
+            _ => return Err(format!("expected RED,GREEN,BLUE,WHITE, got '{}'", color)),
+        },
+    };
+
+    // Report ID 0 first, as for the feature report
+    let mut report = [0; OUTPUT_REPORT_LEN + 1];
+    report[1..].copy_from_slice(&color.encode());
+    device.write(&report).map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+fn apply(settings: &mut Settings, change: &str) -> Result<(), String> {
+    let (key, value) = change
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got '{}'", change))?;
+    match key {
+        "pointer" => settings.pointer_sensitivity = number(value)?,
+        "scroll" => settings.scroll_sensitivity = number(value)?,
+        "led" => {
+            settings.led_mode = match value {
+                "mode" => LedMode::Mode,
+                "off" => LedMode::Off,
+                _ => return Err(format!("unknown LED mode '{}'", value)),
+            }
+        }
+        "button3" => settings.buttons[0] = action(value)?,
+        "button5" => settings.buttons[1] = action(value)?,
+        "tap" => settings.tap_ms = number(value)?,
+        "click" => settings.click_ms = number(value)?,
+        _ => return Err(format!("unknown setting '{}'", key)),
+    }
+    Ok(())
+}
+
+fn action(value: &str) -> Result<Action, String> {
+    let mut parts = value.split(':');
+    let action = match (parts.next(), parts.next(), parts.next()) {
+        (Some("none"), None, None) => Action::None,
+        (Some("mouse"), Some(bits), None) => Action::Mouse(number(bits)?),
+        (Some("key"), Some(modifier), Some(keycode)) => Action::Key {
+            modifier: number(modifier)?,
+            keycode: number(keycode)?,
+        },
+        (Some("consumer"), Some(usage), None) => Action::Consumer(number(usage)?),
+        (Some("scroll"), None, None) => Action::ToggleScroll,
+        (Some("draglock"), None, None) => Action::DragLock,
+        (Some("doubleclick"), None, None) => Action::DoubleClick,
+        _ => return Err(format!("unknown action '{}'", value)),
+    };
+    if parts.next().is_some() {
+        return Err(format!("unknown action '{}'", value));
+    }
+    Ok(action)
+}
+
+/// A USB ID from the environment
+fn id(name: &str, default: u16) -> Result<u16, String> {
+    match env::var(name) {
+        Ok(value) => number(&value).map_err(|err| format!("{}: {}", name, err)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn number<T: TryFrom<u32>>(value: &str) -> Result<T, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| format!("bad number '{}'", value))
+}
+
+fn describe(action: Action) -> String {
+    match action {
+        Action::None => "none".into(),
+        Action::Mouse(bits) => format!("mouse:{:#04x}", bits),
+        Action::Key { modifier, keycode } => format!("key:{:#04x}:{:#04x}", modifier, keycode),
+        Action::Consumer(usage) => format!("consumer:{:#06x}", usage),
+        Action::ToggleScroll => "scroll".into(),
+        Action::DragLock => "draglock".into(),
+        Action::DoubleClick => "doubleclick".into(),
+    }
+}
+
+fn print(settings: &Settings) {
+    println!("pointer={}", settings.pointer_sensitivity);
+    println!("scroll={}", settings.scroll_sensitivity);
+    match settings.led_mode {
+        LedMode::Mode => println!("led=mode"),
+        LedMode::Off => println!("led=off"),
+    }
+    println!("button3={}", describe(settings.buttons[0]));
+    println!("button5={}", describe(settings.buttons[1]));
+    println!("tap={}", settings.tap_ms);
+    println!("click={}", settings.click_ms);
+}
@@ -0,0 +1,378 @@
+//! Runtime settings of the STM32F0 blackberry trackball, and their binary layout in the
+//! vendor-defined HID feature report.
+//!
+//! The firmware answers GET_REPORT with the encoded current settings and applies whatever a
+//! SET_REPORT carries, so the `bbtrackball-config` host tool and the firmware share this crate.
+//!
+//! Layout of the feature report (no report ID), version 1:
+//!
+//! | Offset | Size | Field                                    |
+//! |--------|------|------------------------------------------|
+//! | 0      | 1    | Layout version, currently 1              |
+//! | 1      | 1    | Pointer sensitivity, 1..=127             |
+//! | 2      | 1    | Scroll sensitivity, 1..=127              |
+//! | 3      | 1    | LED mode                                 |
+//! | 4      | 4    | Action of aux button 3 (PA15)            |
+//! | 8      | 4    | Action of aux button 5 (PB3)             |
+//! | 12     | 2    | Drag-lock tap ms, LE, 0 for default      |
+//! | 14     | 2    | Double-click step ms, LE, 0 for default  |
+//!
+//! Each action is a kind byte followed by three argument bytes, see [`Action`].
+//!
+//! The same interface also takes a 4 byte output report with an LED colour, see [`Color`].
+//!
+//! The firmware keeps the settings in flash across power cycles, see [`store`].
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod store;
+
+/// Current version of the report layout
+pub const VERSION: u8 = 1;
+
+/// Length of the feature report in bytes
+pub const REPORT_LEN: usize = 16;
+
+/// Number of aux buttons that can be mapped
+pub const BUTTONS: usize = 2;
+
+/// Length of the LED colour output report in bytes
+pub const OUTPUT_REPORT_LEN: usize = 4;
+
+/// Vendor-defined usage page of the settings interface, for host tools to find it by
+pub const USAGE_PAGE: u16 = 0xFF00;
+
+/// HID report descriptor of the settings interface: a `REPORT_LEN` byte feature report and an
+/// `OUTPUT_REPORT_LEN` byte output report
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: [u8; 27] = [
+    0x06, 0x00, 0xFF,              // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,                    // Usage (0x01)
+    0xA1, 0x01,                    // Collection (Application)
+    0x09, 0x02,                    //   Usage (0x02)
+    0x15, 0x00,                    //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,              //   Logical Maximum (255)
+    0x75, 0x08,                    //   Report Size (8)
+    0x95, REPORT_LEN as u8,        //   Report Count (REPORT_LEN)
+    0xB1, 0x02,                    //   Feature (Data, Variable, Absolute)
+    0x09, 0x03,                    //   Usage (0x03)
+    0x95, OUTPUT_REPORT_LEN as u8, //   Report Count (OUTPUT_REPORT_LEN)
+    0x91, 0x02,                    //   Output (Data, Variable, Absolute)
+    0xC0,                          // End Collection
+];
+
+/// What an aux button does while it is held down
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Does nothing
+    None,
+    /// Hold mouse buttons (bit 0 left, bit 1 right, bit 2 middle)
+    Mouse(u8),
+    /// Hold a key plus modifiers, see the keyboard page (0x07) of the HID usage tables.
+    /// For example Ctrl+C is `Key { modifier: 0x01, keycode: 0x06 }`.
+    Key { modifier: u8, keycode: u8 },
+    /// Hold a consumer control usage, e.g. `0xCD` for play/pause
+    Consumer(u16),
+    /// Switch the trackball between pointer and scroll mode on press
+    ToggleScroll,
+    /// Hold the left mouse button while pressed. A tap shorter than [`Settings::tap_ms`]
+    /// locks it down for dragging, the next press and release lets go.
+    DragLock,
+    /// Send a left double click on press, each half [`Settings::click_ms`] long
+    DoubleClick,
+}
+
+/// What the trackball LEDs show
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LedMode {
+    /// White in pointer mode, blue in scroll mode
+    Mode,
+    /// Always off
+    Off,
+}
+
+/// Brightness of each trackball LED, 0 is off and 255 full brightness
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub white: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color::new(0, 0, 0, 0);
+
+    pub const fn new(red: u8, green: u8, blue: u8, white: u8) -> Self {
+        Color {
+            red,
+            green,
+            blue,
+            white,
+        }
+    }
+
+    /// Encode into the output report layout: red, green, blue, white
+    pub fn encode(&self) -> [u8; OUTPUT_REPORT_LEN] {
+        [self.red, self.green, self.blue, self.white]
+    }
+
+    /// Decode an output report. All zero hands the LEDs back to the firmware, which is `None`.
+    pub fn decode(report: &[u8]) -> Result<Option<Self>, Error> {
+        match *report {
+            [red, green, blue, white, ..] => {
+                let color = Color::new(red, green, blue, white);
+                Ok(if color == Color::OFF {
+                    None
+                } else {
+                    Some(color)
+                })
+            }
+            _ => Err(Error::TooShort),
+        }
+    }
+}
+
+/// Everything about the trackball that can be changed at runtime
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Pointer movement per trackball pulse
+    pub pointer_sensitivity: u8,
+    /// Wheel/pan movement per trackball pulse while in scroll mode
+    pub scroll_sensitivity: u8,
+    pub led_mode: LedMode,
+    /// Actions of the aux buttons, PA15 first and PB3 second
+    pub buttons: [Action; BUTTONS],
+    /// Longest press in ms that still counts as a tap for [`Action::DragLock`]
+    pub tap_ms: u16,
+    /// How long in ms the button is down, and then up, for each click of
+    /// [`Action::DoubleClick`]
+    pub click_ms: u16,
+}
+
+/// Why a report could not be decoded
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The report is shorter than `REPORT_LEN` or `OUTPUT_REPORT_LEN`
+    TooShort,
+    /// The report was written for a layout version this side does not know
+    UnsupportedVersion(u8),
+    /// A sensitivity is zero or does not fit in an `i8`
+    InvalidSensitivity(u8),
+    /// Unknown LED mode
+    InvalidLedMode(u8),
+    /// Unknown action kind
+    InvalidAction(u8),
+}
+
+impl Action {
+    fn encode(self) -> [u8; 4] {
+        match self {
+            Action::None => [0, 0, 0, 0],
+            Action::Mouse(bits) => [1, bits, 0, 0],
+            Action::Key { modifier, keycode } => [2, modifier, keycode, 0],
+            Action::Consumer(usage_id) => {
+                let [lo, hi] = usage_id.to_le_bytes();
+                [3, lo, hi, 0]
+            }
+            Action::ToggleScroll => [4, 0, 0, 0],
+            Action::DragLock => [5, 0, 0, 0],
+            Action::DoubleClick => [6, 0, 0, 0],
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(match bytes[0] {
+            0 => Action::None,
+            1 => Action::Mouse(bytes[1]),
+            2 => Action::Key {
+                modifier: bytes[1],
+                keycode: bytes[2],
+            },
+            3 => Action::Consumer(u16::from_le_bytes([bytes[1], bytes[2]])),
+            4 => Action::ToggleScroll,
+            5 => Action::DragLock,
+            6 => Action::DoubleClick,
+            kind => return Err(Error::InvalidAction(kind)),
+        })
+    }
+}
+
+impl LedMode {
+    fn decode(byte: u8) -> Result<Self, Error> {
+        match byte {
+            0 => Ok(LedMode::Mode),
+            1 => Ok(LedMode::Off),
+            other => Err(Error::InvalidLedMode(other)),
+        }
+    }
+}
+
+/// A gesture time, where 0 on the wire picks the default
+fn time(bytes: &[u8], default: u16) -> u16 {
+    match u16::from_le_bytes([bytes[0], bytes[1]]) {
+        0 => default,
+        ms => ms,
+    }
+}
+
+fn sensitivity(byte: u8) -> Result<u8, Error> {
+    match byte {
+        1..=127 => Ok(byte),
+        other => Err(Error::InvalidSensitivity(other)),
+    }
+}
+
+impl Settings {
+    /// Drag-lock tap time used when the report leaves it at 0
+    pub const DEFAULT_TAP_MS: u16 = 200;
+    /// Double-click step time used when the report leaves it at 0. The mouse endpoint is
+    /// polled every 60 ms, so anything shorter may merge the clicks.
+    pub const DEFAULT_CLICK_MS: u16 = 70;
+
+    /// Encode into the feature report layout
+    pub fn encode(&self) -> [u8; REPORT_LEN] {
+        let mut report = [0; REPORT_LEN];
+        report[0] = VERSION;
+        report[1] = self.pointer_sensitivity;
+        report[2] = self.scroll_sensitivity;
+        report[3] = self.led_mode as u8;
+        for (chunk, action) in report[4..].chunks_mut(4).zip(self.buttons.iter()) {
+            chunk.copy_from_slice(&action.encode());
+        }
+        report[12..14].copy_from_slice(&self.tap_ms.to_le_bytes());
+        report[14..16].copy_from_slice(&self.click_ms.to_le_bytes());
+        report
+    }
+
+    /// Decode and validate a feature report
+    pub fn decode(report: &[u8]) -> Result<Self, Error> {
+        if report.len() < REPORT_LEN {
+            return Err(Error::TooShort);
+        }
+        if report[0] != VERSION {
+            return Err(Error::UnsupportedVersion(report[0]));
+        }
+
+        let mut buttons = [Action::None; BUTTONS];
+        for (action, chunk) in buttons.iter_mut().zip(report[4..].chunks(4)) {
+            *action = Action::decode(chunk)?;
+        }
+
+        Ok(Settings {
+            pointer_sensitivity: sensitivity(report[1])?,
+            scroll_sensitivity: sensitivity(report[2])?,
+            led_mode: LedMode::decode(report[3])?,
+            buttons,
+            tap_ms: time(&report[12..], Self::DEFAULT_TAP_MS),
+            click_ms: time(&report[14..], Self::DEFAULT_CLICK_MS),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            pointer_sensitivity: 5,
+            scroll_sensitivity: 1,
+            led_mode: LedMode::Mode,
+            buttons: [Action::Mouse(0x01), Action::ToggleScroll],
+            tap_ms: Settings::DEFAULT_TAP_MS,
+            click_ms: Settings::DEFAULT_CLICK_MS,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let all_actions = [
+            Action::None,
+            Action::Mouse(0x05),
+            Action::Key {
+                modifier: 0x01,
+                keycode: 0x06,
+            },
+            Action::Consumer(0x00CD),
+            Action::ToggleScroll,
+            Action::DragLock,
+            Action::DoubleClick,
+        ];
+        for &first in &all_actions {
+            for &second in &all_actions {
+                let settings = Settings {
+                    pointer_sensitivity: 127,
+                    scroll_sensitivity: 3,
+                    led_mode: LedMode::Off,
+                    buttons: [first, second],
+                    tap_ms: 300,
+                    click_ms: 1,
+                };
+                assert_eq!(Settings::decode(&settings.encode()), Ok(settings));
+            }
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let mut settings = settings();
+        settings.buttons[1] = Action::Consumer(0x01E2);
+        settings.tap_ms = 0x0123;
+        assert_eq!(
+            settings.encode(),
+            [1, 5, 1, 0, 1, 0x01, 0, 0, 3, 0xE2, 0x01, 0, 0x23, 0x01, 70, 0]
+        );
+    }
+
+    #[test]
+    fn default_times() {
+        // Reports from before the gesture times existed have zeros there
+        let mut report = settings().encode();
+        report[12..].fill(0);
+        assert_eq!(Settings::decode(&report), Ok(settings()));
+    }
+
+    #[test]
+    fn rejects_bad_reports() {
+        let report = settings().encode();
+        assert_eq!(Settings::decode(&report[..8]), Err(Error::TooShort));
+
+        let mut bad = report;
+        bad[0] = 2;
+        assert_eq!(Settings::decode(&bad), Err(Error::UnsupportedVersion(2)));
+
+        let mut bad = report;
+        bad[1] = 0;
+        assert_eq!(Settings::decode(&bad), Err(Error::InvalidSensitivity(0)));
+
+        let mut bad = report;
+        bad[2] = 128;
+        assert_eq!(Settings::decode(&bad), Err(Error::InvalidSensitivity(128)));
+
+        let mut bad = report;
+        bad[3] = 7;
+        assert_eq!(Settings::decode(&bad), Err(Error::InvalidLedMode(7)));
+
+        let mut bad = report;
+        bad[8] = 9;
+        assert_eq!(Settings::decode(&bad), Err(Error::InvalidAction(9)));
+    }
+
+    #[test]
+    fn color() {
+        let color = Color::new(1, 2, 3, 4);
+        assert_eq!(color.encode(), [1, 2, 3, 4]);
+        assert_eq!(Color::decode(&color.encode()), Ok(Some(color)));
+        assert_eq!(Color::decode(&[0; OUTPUT_REPORT_LEN]), Ok(None));
+        assert_eq!(Color::decode(&[1, 2, 3]), Err(Error::TooShort));
+    }
+
+    #[test]
+    fn accepts_longer_reports() {
+        // Some hosts pad reports, anything past REPORT_LEN is ignored
+        let mut report = [0; REPORT_LEN + 4];
+        report[..REPORT_LEN].copy_from_slice(&settings().encode());
+        assert_eq!(Settings::decode(&report), Ok(settings()));
+    }
+}
@@ -0,0 +1,283 @@
+//! Wear-levelled storage of [`Settings`] in a few pages of flash.
+//!
+//! Every save appends a record to the storage area rather than rewriting the same place, so a
+//! page is only erased once it is full. Records never cross a page boundary. When the page in
+//! use is full, the next page (wrapping around) is erased and written from its start, so with at
+//! least two pages the latest record survives a save that is interrupted by a power loss.
+//!
+//! Layout of a record:
+//!
+//! | Offset | Size | Field                                                   |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | 2    | Sequence number, one up with every save (wrapping)      |
+//! | 2      | 16   | Settings report, its first byte is the layout version   |
+//! | 18     | 2    | CRC-16/CCITT-FALSE of the previous 18 bytes             |
+//!
+//! All integers are little endian. Records with a bad CRC or settings that do not decode, e.g.
+//! written by firmware with a different layout version, are ignored.
+
+use crate::{Settings, REPORT_LEN};
+
+/// Length of a record in bytes, a multiple of the 16 bit flash programming width
+pub const RECORD_LEN: usize = 2 + REPORT_LEN + 2;
+
+/// Value of erased flash
+const ERASED: u8 = 0xFF;
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, no reflection
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Build the record for a save
+pub fn encode_record(seq: u16, settings: &Settings) -> [u8; RECORD_LEN] {
+    let mut record = [0; RECORD_LEN];
+    record[..2].copy_from_slice(&seq.to_le_bytes());
+    record[2..2 + REPORT_LEN].copy_from_slice(&settings.encode());
+    let crc = crc16(&record[..RECORD_LEN - 2]);
+    record[RECORD_LEN - 2..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// Sequence number and settings of a record.
+/// `None` for erased or partially written slots, CRC errors and settings that do not decode.
+pub fn decode_record(record: &[u8]) -> Option<(u16, Settings)> {
+    let record = record.get(..RECORD_LEN)?;
+    let crc = u16::from_le_bytes([record[RECORD_LEN - 2], record[RECORD_LEN - 1]]);
+    if crc != crc16(&record[..RECORD_LEN - 2]) {
+        return None;
+    }
+
+    let seq = u16::from_le_bytes([record[0], record[1]]);
+    let settings = Settings::decode(&record[2..2 + REPORT_LEN]).ok()?;
+    Some((seq, settings))
+}
+
+/// The most recently saved record in a storage area
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Latest {
+    /// Offset of the record from the start of the area
+    pub offset: usize,
+    pub seq: u16,
+    pub settings: Settings,
+}
+
+/// Where the next record goes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Write {
+    /// Offset of a page to erase first
+    pub erase: Option<usize>,
+    /// Offset of the record from the start of the area
+    pub offset: usize,
+    /// Sequence number of the record
+    pub seq: u16,
+}
+
+/// Offsets of all record slots in an area of whole pages
+fn slots(area_len: usize, page_size: usize) -> impl Iterator<Item = usize> {
+    (0..area_len / page_size).flat_map(move |page| {
+        (0..page_size / RECORD_LEN).map(move |slot| page * page_size + slot * RECORD_LEN)
+    })
+}
+
+/// Is sequence number `a` newer than `b`, allowing for wrap around?
+fn is_newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+fn is_erased(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&byte| byte == ERASED)
+}
+
+/// Find the latest valid record in `area`, which is made of `page_size` byte pages
+pub fn find_latest(area: &[u8], page_size: usize) -> Option<Latest> {
+    slots(area.len(), page_size)
+        .filter_map(|offset| {
+            decode_record(&area[offset..]).map(|(seq, settings)| Latest {
+                offset,
+                seq,
+                settings,
+            })
+        })
+        .fold(None, |latest: Option<Latest>, record| match latest {
+            Some(latest) if !is_newer(record.seq, latest.seq) => Some(latest),
+            _ => Some(record),
+        })
+}
+
+/// Decide where to write the next record in `area`, which is made of `page_size` byte pages
+pub fn plan_write(area: &[u8], page_size: usize) -> Write {
+    let next_page = |offset: usize| (offset / page_size + 1) * page_size % area.len();
+    let fresh_page = |offset: usize, seq: u16| Write {
+        erase: if is_erased(&area[offset..offset + page_size]) {
+            None
+        } else {
+            Some(offset)
+        },
+        offset,
+        seq,
+    };
+
+    let (offset, seq) = match find_latest(area, page_size) {
+        Some(latest) => (latest.offset + RECORD_LEN, latest.seq.wrapping_add(1)),
+        None => (0, 0),
+    };
+
+    if offset % page_size + RECORD_LEN > page_size {
+        // Page full
+        fresh_page(next_page(offset - RECORD_LEN), seq)
+    } else if offset % page_size == 0 {
+        fresh_page(offset, seq)
+    } else if is_erased(&area[offset..offset + RECORD_LEN]) {
+        Write {
+            erase: None,
+            offset,
+            seq,
+        }
+    } else {
+        // Left over from an interrupted save, carry on with the next page
+        fresh_page(next_page(offset), seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Action, LedMode};
+
+    const PAGE_SIZE: usize = 64;
+    const SLOTS_PER_PAGE: usize = PAGE_SIZE / RECORD_LEN;
+
+    fn settings(pointer_sensitivity: u8) -> Settings {
+        Settings {
+            pointer_sensitivity,
+            scroll_sensitivity: 1,
+            led_mode: LedMode::Mode,
+            buttons: [Action::Mouse(0x01), Action::ToggleScroll],
+            tap_ms: Settings::DEFAULT_TAP_MS,
+            click_ms: Settings::DEFAULT_CLICK_MS,
+        }
+    }
+
+    /// Simulated flash: two pages, starting out erased
+    struct Flash([u8; 2 * PAGE_SIZE]);
+
+    impl Flash {
+        fn new() -> Self {
+            Flash([ERASED; 2 * PAGE_SIZE])
+        }
+
+        fn save(&mut self, settings: &Settings) -> Write {
+            let write = plan_write(&self.0, PAGE_SIZE);
+            if let Some(page) = write.erase {
+                self.0[page..page + PAGE_SIZE].fill(ERASED);
+            }
+            let record = encode_record(write.seq, settings);
+            let slot = &mut self.0[write.offset..write.offset + RECORD_LEN];
+            assert!(is_erased(slot), "writing over {:?}", write);
+            slot.copy_from_slice(&record);
+            write
+        }
+
+        fn load(&self) -> Option<Settings> {
+            find_latest(&self.0, PAGE_SIZE).map(|latest| latest.settings)
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let record = encode_record(0x1234, &settings(7));
+        assert_eq!(&record[..2], &[0x34, 0x12]);
+        assert_eq!(decode_record(&record), Some((0x1234, settings(7))));
+    }
+
+    #[test]
+    fn rejects_corrupted_records() {
+        assert_eq!(decode_record(&[ERASED; RECORD_LEN]), None);
+        assert_eq!(decode_record(&[0; RECORD_LEN - 1]), None);
+
+        for byte in 0..RECORD_LEN {
+            let mut record = encode_record(1, &settings(7));
+            record[byte] ^= 0x10;
+            assert_eq!(decode_record(&record), None, "flipped byte {}", byte);
+        }
+
+        // Valid CRC, but a layout version this firmware does not know
+        let mut record = encode_record(1, &settings(7));
+        record[2] = 0xEE;
+        let crc = crc16(&record[..RECORD_LEN - 2]);
+        record[RECORD_LEN - 2..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(decode_record(&record), None);
+    }
+
+    #[test]
+    fn empty_area() {
+        let flash = Flash::new();
+        assert_eq!(flash.load(), None);
+        assert_eq!(
+            plan_write(&flash.0, PAGE_SIZE),
+            Write {
+                erase: None,
+                offset: 0,
+                seq: 0
+            }
+        );
+
+        // Garbage rather than erased flash
+        let flash = Flash([0; 2 * PAGE_SIZE]);
+        assert_eq!(flash.load(), None);
+        assert_eq!(plan_write(&flash.0, PAGE_SIZE).erase, Some(0));
+    }
+
+    #[test]
+    fn levels_wear_across_pages() {
+        let mut flash = Flash::new();
+        let mut erases = [0; 2];
+        for i in 0..10 * SLOTS_PER_PAGE as u8 {
+            let write = flash.save(&settings(i + 1));
+            if let Some(page) = write.erase {
+                erases[page / PAGE_SIZE] += 1;
+            }
+            assert_eq!(flash.load(), Some(settings(i + 1)));
+        }
+        // Ten pages filled, the first two of which started out erased
+        assert_eq!(erases, [4, 4]);
+    }
+
+    #[test]
+    fn survives_sequence_wrap() {
+        let mut flash = Flash::new();
+        let record = encode_record(u16::MAX - 1, &settings(1));
+        flash.0[..RECORD_LEN].copy_from_slice(&record);
+        for i in 2..6 {
+            flash.save(&settings(i));
+            assert_eq!(flash.load(), Some(settings(i)));
+        }
+    }
+
+    #[test]
+    fn skips_interrupted_save() {
+        let mut flash = Flash::new();
+        flash.save(&settings(1));
+        // Power lost halfway through writing the second record
+        flash.0[RECORD_LEN..RECORD_LEN + 4].fill(0);
+        assert_eq!(flash.load(), Some(settings(1)));
+
+        let write = flash.save(&settings(2));
+        assert_eq!(write.offset, PAGE_SIZE);
+        assert_eq!(flash.load(), Some(settings(2)));
+    }
+}
@@ -0,0 +1,120 @@
+//! Edge sources that the gate task samples once per gate period.
+//!
+//! By default every falling edge on PA0 raises EXTI0 and bumps an atomic
+//! counter, which tops out somewhere in the hundreds of kHz. With the
+//! `timer-counter` feature PA0 instead drives TIM2's external trigger input
+//! (ETR, AF1) in external clock mode 2, so the timer counts the edges in
+//! hardware and the CPU only reads the count once per gate.
+
+#[cfg(not(feature = "timer-counter"))]
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "timer-counter")]
+use stm32f4xx_hal::{
+    gpio::{Alternate, PA0},
+    pac::TIM2,
+    rcc::Clocks,
+    timer::Timer,
+};
+
+/// Edges counted by the EXTI0 handler since the last gate.
+#[cfg(not(feature = "timer-counter"))]
+pub static EDGES: AtomicU32 = AtomicU32::new(0);
+
+/// Edges rejected by the EXTI0 handler's dead time since the last gate.
+#[cfg(not(feature = "timer-counter"))]
+pub static GLITCHES: AtomicU32 = AtomicU32::new(0);
+
+/// Counts the falling edges seen by the EXTI0 handler.
+#[cfg(not(feature = "timer-counter"))]
+pub struct Counter;
+
+#[cfg(not(feature = "timer-counter"))]
+impl Counter {
+    /// Returns the edges counted since the last call.
+    pub fn take(&mut self) -> u32 {
+        EDGES.swap(0, Ordering::SeqCst)
+    }
+
+    /// Returns the edges rejected as glitches since the last call.
+    pub fn take_glitches(&mut self) -> u32 {
+        GLITCHES.swap(0, Ordering::SeqCst)
+    }
+}
+
+/// Rejects edges that follow the last accepted one within a dead time, such
+/// as the bounces of a mechanical contact.
+///
+/// Works on DWT cycle counts, so an edge that follows the last accepted one
+/// by a multiple of the counter's 89 s wrap, give or take the dead time, is
+/// rejected too.
+pub struct Debounce {
+    dead_time_cycles: u32,
+    last: Option<u32>,
+}
+
+impl Debounce {
+    pub const fn new(dead_time_cycles: u32) -> Self {
+        Self {
+            dead_time_cycles,
+            last: None,
+        }
+    }
+
+    /// Returns whether the edge at `cycles` counts.
+    pub fn accept(&mut self, cycles: u32) -> bool {
+        match self.last {
+            Some(last) if cycles.wrapping_sub(last) < self.dead_time_cycles => false,
+            _ => {
+                self.last = Some(cycles);
+                true
+            }
+        }
+    }
+}
+
+/// Counts the falling edges on PA0 with TIM2 clocked from its ETR input.
+///
+/// ETR is resynchronised to the timer clock, so the highest countable rate is
+/// a quarter of it: 12 MHz with the 48 MHz timer clock set up in `init`.
+#[cfg(feature = "timer-counter")]
+pub struct Counter {
+    tim: TIM2,
+    _pin: PA0<Alternate<1>>,
+    last: u32,
+}
+
+#[cfg(feature = "timer-counter")]
+impl Counter {
+    pub fn new(tim: TIM2, pin: PA0<Alternate<1>>, clocks: &Clocks) -> Self {
+        // Enables and resets TIM2, the counter itself is set up by hand.
+        let tim = Timer::new(tim, clocks).release();
+
+        tim.smcr.write(|w| {
+            w.ece().enabled();
+            // Count falling edges, like the EXTI mode does.
+            w.etp().inverted();
+            w.etps().div1();
+            w.etf().no_filter()
+        });
+        tim.arr.write(|w| w.arr().bits(u32::MAX));
+        tim.cr1.modify(|_, w| w.cen().enabled());
+
+        Self {
+            tim,
+            _pin: pin,
+            last: 0,
+        }
+    }
+
+    /// Returns the edges counted since the last call.
+    ///
+    /// TIM2 free-runs over its full 32 bits and is never stopped or cleared,
+    /// so no edges are lost between gates and the difference of two reads is
+    /// correct across a wrap.
+    pub fn take(&mut self) -> u32 {
+        let now = self.tim.cnt.read().cnt().bits();
+        let edges = now.wrapping_sub(self.last);
+        self.last = now;
+        edges
+    }
+}
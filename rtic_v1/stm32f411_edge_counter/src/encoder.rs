@@ -0,0 +1,47 @@
+//! Quadrature encoder on TIM3's encoder interface (PA6 = A, PA7 = B).
+//!
+//! TIM3 counts every edge of both channels, four counts per encoder line,
+//! up or down with the direction of rotation. Its counter is only 16 bits
+//! wide, so the `poll_encoder` task folds it into a 64-bit position far more
+//! often than the gate period, before it can move by half its range.
+
+use stm32f4xx_hal::{pac::TIM3, prelude::*, qei::Qei};
+
+pub struct Encoder {
+    qei: Qei<TIM3>,
+    last_count: u16,
+    position: i64,
+    gate_position: i64,
+}
+
+impl Encoder {
+    pub fn new(qei: Qei<TIM3>) -> Self {
+        let last_count = qei.count();
+
+        Self {
+            qei,
+            last_count,
+            position: 0,
+            gate_position: 0,
+        }
+    }
+
+    /// Folds the hardware counter into the extended position and returns it.
+    ///
+    /// The difference to the last read is taken as a signed 16-bit value, so
+    /// this has to be called before the counter moves by 32768 counts.
+    pub fn update(&mut self) -> i64 {
+        let count = self.qei.count();
+        self.position += i64::from(count.wrapping_sub(self.last_count) as i16);
+        self.last_count = count;
+        self.position
+    }
+
+    /// Returns the position and the counts moved since the last gate.
+    pub fn gate(&mut self) -> (i64, i64) {
+        let position = self.update();
+        let moved = position - self.gate_position;
+        self.gate_position = position;
+        (position, moved)
+    }
+}
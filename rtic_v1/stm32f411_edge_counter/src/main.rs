@@ -6,9 +6,20 @@
 use panic_rtt_target as _panic_handler;
 use rtic::app;
 
-#[app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [SPI1])]
+mod counter;
+mod encoder;
+mod timing;
+
+#[app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [SPI1, SPI2])]
 mod app {
-    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{
+        counter::{Counter, Debounce},
+        encoder::Encoder,
+        timing::{EdgeQueue, Intervals, Timestamp, DROPPED, QUEUE_LEN},
+    };
+    use core::sync::atomic::Ordering;
+    use cortex_m::peripheral::DWT;
+    use heapless::spsc::{Consumer, Producer};
     use rtt_target::{rprintln, rtt_init_print};
     use stm32f4xx_hal::{
         gpio::{Edge, Input, Output, PA0, PC13},
@@ -16,57 +27,189 @@ mod app {
     };
     use systick_monotonic::{fugit::ExtU64, Systick};
 
-    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    const SYSCLK_MHZ: u32 = 48;
+    /// Time over which edges are counted and the encoder's velocity is taken.
+    const GATE_MS: u64 = 1000;
+    /// How often the encoder's 16-bit counter is folded into its position.
+    /// Good for up to 3.2 million counts per second.
+    const ENCODER_POLL_MS: u64 = 10;
+    /// Edges per shaft revolution, for the RPM reported from the edge
+    /// intervals.
+    const PULSES_PER_REVOLUTION: u32 = 1;
+    /// Edges on PA0 that follow the last counted one sooner than this are
+    /// counted as glitches instead. 0 counts every edge, a millisecond or so
+    /// suits mechanical contacts.
+    const DEAD_TIME_US: u32 = 0;
 
     #[shared]
-    struct Shared {}
+    struct Shared {
+        #[lock_free]
+        encoder: Encoder,
+        intervals: Intervals,
+    }
 
     #[local]
     struct Local {
         led: PC13<Output>,
+        counter: Counter,
+        #[cfg(not(feature = "timer-counter"))]
         pin: PA0<Input>,
+        producer: Producer<'static, Timestamp, QUEUE_LEN>,
+        consumer: Consumer<'static, Timestamp, QUEUE_LEN>,
     }
 
     #[monotonic(binds = SysTick, default = true)]
     type Tonic = Systick<1000>;
 
-    #[init]
-    fn init(mut ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+    #[init(local = [queue: EdgeQueue = EdgeQueue::new()])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         rtt_init_print!();
         rprintln!("init");
 
         let rcc = ctx.device.RCC.constrain();
-        let _clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
+        let clocks = rcc.cfgr.sysclk(SYSCLK_MHZ.MHz()).freeze();
 
         let gpioc = ctx.device.GPIOC.split();
         let led = gpioc.pc13.into_push_pull_output();
 
         let gpioa = ctx.device.GPIOA.split();
-        let mut pin = gpioa.pa0.into_pull_up_input();
-        let mut sys_cfg = ctx.device.SYSCFG.constrain();
-        pin.make_interrupt_source(&mut sys_cfg);
-        pin.enable_interrupt(&mut ctx.device.EXTI);
-        pin.trigger_on_edge(&mut ctx.device.EXTI, Edge::Falling);
 
-        let mono = Systick::new(ctx.core.SYST, 48_000_000);
+        #[cfg(not(feature = "timer-counter"))]
+        let (counter, pin) = {
+            let mut pin = gpioa.pa0.into_pull_up_input();
+            let mut sys_cfg = ctx.device.SYSCFG.constrain();
+            let mut exti = ctx.device.EXTI;
+            pin.make_interrupt_source(&mut sys_cfg);
+            pin.enable_interrupt(&mut exti);
+            pin.trigger_on_edge(&mut exti, Edge::Falling);
+
+            let mut dcb = ctx.core.DCB;
+            let mut dwt = ctx.core.DWT;
+            dcb.enable_trace();
+            dwt.enable_cycle_counter();
+
+            (Counter, pin)
+        };
+
+        #[cfg(feature = "timer-counter")]
+        let counter = Counter::new(ctx.device.TIM2, gpioa.pa0.into_alternate(), &clocks);
+
+        let encoder = Encoder::new(
+            ctx.device
+                .TIM3
+                .qei((gpioa.pa6.into_alternate(), gpioa.pa7.into_alternate())),
+        );
+
+        // Stays empty with the timer counter, which has no edges to stamp.
+        let (producer, consumer) = ctx.local.queue.split();
+        let intervals = Intervals::new(clocks.sysclk().raw(), PULSES_PER_REVOLUTION);
+
+        let mono = Systick::new(ctx.core.SYST, clocks.sysclk().raw());
 
         blink::spawn().ok();
+        poll_encoder::spawn().ok();
 
-        (Shared {}, Local { pin, led }, init::Monotonics(mono))
+        (
+            Shared { encoder, intervals },
+            Local {
+                led,
+                counter,
+                #[cfg(not(feature = "timer-counter"))]
+                pin,
+                producer,
+                consumer,
+            },
+            init::Monotonics(mono),
+        )
     }
 
-    #[task(local = [led], priority = 4)]
-    fn blink(ctx: blink::Context) {
-        let count = COUNTER.swap(0, Ordering::SeqCst);
+    #[task(local = [led, counter], shared = [encoder, intervals], priority = 4)]
+    fn blink(mut ctx: blink::Context) {
+        let count = ctx.local.counter.take();
+        #[cfg(not(feature = "timer-counter"))]
+        rprintln!("{} ({} glitches)", count, ctx.local.counter.take_glitches());
+        #[cfg(feature = "timer-counter")]
         rprintln!("{}", count);
+
+        if let Some(report) = ctx.shared.intervals.lock(|intervals| intervals.take()) {
+            rprintln!(
+                "period: {:.1} us ({:.2} Hz, {:.1} rpm), min {:.1} us, max {:.1} us over {} intervals",
+                report.avg_us,
+                report.frequency_hz,
+                report.rpm,
+                report.min_us,
+                report.max_us,
+                report.intervals
+            );
+        }
+        let dropped = DROPPED.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            rprintln!("{} edges dropped on a full queue", dropped);
+        }
+
+        let (position, moved) = ctx.shared.encoder.gate();
+        rprintln!(
+            "encoder: {} counts, {} counts/s",
+            position,
+            moved * 1000 / GATE_MS as i64
+        );
+
         ctx.local.led.toggle();
-        blink::spawn_after(ExtU64::millis(1000)).ok();
+        blink::spawn_after(ExtU64::millis(GATE_MS)).ok();
+    }
+
+    /// Shares `blink`'s priority, so the two never preempt each other.
+    #[task(shared = [encoder], priority = 4)]
+    fn poll_encoder(ctx: poll_encoder::Context) {
+        ctx.shared.encoder.update();
+        poll_encoder::spawn_after(ExtU64::millis(ENCODER_POLL_MS)).ok();
     }
 
-    #[task(binds = EXTI0, local = [pin])]
+    /// Runs above everything else, so the timestamps don't jitter with the
+    /// other tasks.
+    #[cfg(not(feature = "timer-counter"))]
+    #[task(binds = EXTI0, local = [
+        pin,
+        producer,
+        gap: bool = false,
+        debounce: Debounce = Debounce::new(DEAD_TIME_US * SYSCLK_MHZ),
+    ], priority = 5)]
     fn on_exti(ctx: on_exti::Context) {
+        let cycles = DWT::cycle_count();
         ctx.local.pin.clear_interrupt_pending_bit();
-        rprintln!("incrementing");
-        COUNTER.fetch_add(1, Ordering::SeqCst);
+
+        if !ctx.local.debounce.accept(cycles) {
+            crate::counter::GLITCHES.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        crate::counter::EDGES.fetch_add(1, Ordering::SeqCst);
+
+        let timestamp = Timestamp {
+            cycles,
+            after_gap: *ctx.local.gap,
+        };
+        // `intervals` drains the queue, so it only needs waking up for the first edge in it
+        let was_empty = ctx.local.producer.len() == 0;
+        match ctx.local.producer.enqueue(timestamp) {
+            Ok(()) => {
+                *ctx.local.gap = false;
+                if was_empty {
+                    intervals::spawn().ok();
+                }
+            }
+            Err(_) => {
+                *ctx.local.gap = true;
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[task(local = [consumer], shared = [intervals], priority = 2)]
+    fn intervals(mut ctx: intervals::Context) {
+        while let Some(timestamp) = ctx.local.consumer.dequeue() {
+            ctx.shared
+                .intervals
+                .lock(|intervals| intervals.edge(timestamp));
+        }
     }
 }
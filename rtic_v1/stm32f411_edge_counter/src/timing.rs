@@ -0,0 +1,106 @@
+//! Inter-arrival times of the edges counted on EXTI0.
+//!
+//! `on_exti` stamps every edge with the DWT cycle counter and pushes it into
+//! an SPSC queue, leaving all the arithmetic to the `intervals` task. The
+//! 1 kHz SysTick monotonic is far too coarse to time edges with, while the
+//! cycle counter resolves 1/48 µs. It wraps every 89 s, so longer gaps
+//! between two edges are not measured correctly.
+
+use core::sync::atomic::AtomicU32;
+use heapless::spsc::Queue;
+
+/// The queue holds one edge less than this.
+pub const QUEUE_LEN: usize = 64;
+
+pub type EdgeQueue = Queue<Timestamp, QUEUE_LEN>;
+
+/// Edges that did not fit into the queue since the last gate, and are missing from the
+/// intervals
+pub static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+pub struct Timestamp {
+    /// DWT cycle count when the edge was seen.
+    pub cycles: u32,
+    /// Edges were dropped on a full queue right before this one.
+    pub after_gap: bool,
+}
+
+/// Inter-arrival statistics over one gate period.
+pub struct Report {
+    pub intervals: u32,
+    pub min_us: f32,
+    pub avg_us: f32,
+    pub max_us: f32,
+    pub frequency_hz: f32,
+    pub rpm: f32,
+}
+
+pub struct Intervals {
+    cycles_per_us: f32,
+    pulses_per_revolution: u32,
+    last: Option<u32>,
+    count: u32,
+    sum: u64,
+    min: u32,
+    max: u32,
+}
+
+impl Intervals {
+    pub fn new(sysclk_hz: u32, pulses_per_revolution: u32) -> Self {
+        Self {
+            cycles_per_us: sysclk_hz as f32 / 1_000_000.0,
+            pulses_per_revolution,
+            last: None,
+            count: 0,
+            sum: 0,
+            min: u32::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn edge(&mut self, edge: Timestamp) {
+        if edge.after_gap {
+            // The time since the last edge spans the dropped ones.
+            self.last = None;
+        }
+
+        if let Some(last) = self.last {
+            let interval = edge.cycles.wrapping_sub(last);
+            self.count += 1;
+            self.sum += u64::from(interval);
+            self.min = self.min.min(interval);
+            self.max = self.max.max(interval);
+        }
+
+        self.last = Some(edge.cycles);
+    }
+
+    /// Returns the statistics since the last call, or `None` if no interval
+    /// was measured.
+    ///
+    /// The last edge is kept, so the interval across the gate boundary counts
+    /// towards the next report.
+    pub fn take(&mut self) -> Option<Report> {
+        let report = (self.count > 0).then(|| {
+            let avg_us = self.sum as f32 / self.count as f32 / self.cycles_per_us;
+            let frequency_hz = 1_000_000.0 / avg_us;
+
+            Report {
+                intervals: self.count,
+                min_us: self.min as f32 / self.cycles_per_us,
+                avg_us,
+                max_us: self.max as f32 / self.cycles_per_us,
+                frequency_hz,
+                rpm: frequency_hz * 60.0 / self.pulses_per_revolution as f32,
+            }
+        });
+
+        self.count = 0;
+        self.sum = 0;
+        self.min = u32::MAX;
+        self.max = 0;
+
+        report
+    }
+}
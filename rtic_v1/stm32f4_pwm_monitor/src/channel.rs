@@ -0,0 +1,199 @@
+use crate::app::{tim1_cc, tim2, tim3, tim4, tim5, tim8_cc, PulseFilter, CHANNELS};
+use crate::watchdog::now_ms;
+use pwm_monitor_core::calibration::{self, Calibration};
+use pwm_monitor_core::range::{AutoRange, Range};
+use pwm_monitor_core::watchdog::{TurretPosition, Watchdog};
+use rtic::mutex_prelude::*;
+use stm32f4xx_hal::pac::{TIM1, TIM2, TIM3, TIM4, TIM5, TIM8};
+use stm32f4xx_hal::timer::PwmInput;
+
+/// A timer in PWM input mode. The HAL implements the capture methods for each timer on its own,
+/// this lets one capture routine serve them all.
+pub(crate) trait Capture {
+    /// Period and pulse width in timer clocks, if this interrupt is a valid capture
+    fn capture(&self) -> Option<(u32, u32)>;
+
+    /// Change the prescaler `pwm_input` picked from its best guess, for auto-ranging
+    fn set_prescaler(&mut self, prescaler: u16);
+
+    /// Have the update flag mark counter overflows only, rather than also every rising edge
+    /// resetting the counter, and a prescaler change
+    fn flag_overflows(&mut self);
+
+    /// Whether the counter overflowed since the last call, i.e. a period did not fit in it
+    fn overflowed(&mut self) -> bool;
+}
+
+/// Implements `Capture` for each timer, along with the interrupt handler that feeds its
+/// captures to the `Channel` in the handler's `$channel` local resource
+macro_rules! capture {
+    ($($TIM:ty => $task:ident($channel:ident)),*) => {
+        $(
+            impl Capture for PwmInput<$TIM> {
+                fn capture(&self) -> Option<(u32, u32)> {
+                    // The interrupt fires twice per period, only one of them is a valid capture
+                    self.is_valid_capture().then(|| {
+                        (
+                            self.get_period_clocks().into(),
+                            self.get_duty_cycle_clocks().into(),
+                        )
+                    })
+                }
+
+                // The HAL keeps the prescaler to itself once in PWM input mode
+                #[allow(unsafe_code)]
+                fn set_prescaler(&mut self, prescaler: u16) {
+                    // Safety: the PwmInput owns the timer, nothing else touches its registers
+                    let tim = unsafe { &*<$TIM>::ptr() };
+                    tim.psc.write(|w| w.psc().bits(prescaler));
+                    // load it now rather than at the next overflow, which may never come as
+                    // every rising edge resets the counter
+                    tim.egr.write(|w| w.ug().set_bit());
+                }
+
+                #[allow(unsafe_code)]
+                fn flag_overflows(&mut self) {
+                    // Safety: as in `set_prescaler`
+                    let tim = unsafe { &*<$TIM>::ptr() };
+                    tim.cr1.modify(|_, w| w.urs().set_bit());
+                    tim.sr.write(|w| unsafe { w.bits(0xffff & !1) });
+                }
+
+                #[allow(unsafe_code)]
+                fn overflowed(&mut self) -> bool {
+                    // Safety: as in `set_prescaler`
+                    let tim = unsafe { &*<$TIM>::ptr() };
+                    let overflowed = tim.sr.read().uif().bit_is_set();
+                    if overflowed {
+                        // The other flags are cleared by writing 0, so leave them at 1
+                        tim.sr.write(|w| unsafe { w.bits(0xffff & !1) });
+                    }
+                    overflowed
+                }
+            }
+
+            pub(crate) fn $task(context: $task::Context) {
+                let shared = context.shared;
+                context.local.$channel.on_capture(
+                    shared.last_observed_turret_positions,
+                    shared.raw_turret_positions,
+                    shared.watchdogs,
+                );
+            }
+        )*
+    };
+}
+
+// TIM2 and TIM5 count in 32 bits, so their periods never overflow. A signal that slows down
+// past the band reads back in full there, and re-ranges like one that speeds up.
+capture!(
+    TIM8 => tim8_cc(tim8_channel),
+    TIM1 => tim1_cc(tim1_channel),
+    TIM3 => tim3(tim3_channel),
+    TIM4 => tim4(tim4_channel),
+    TIM2 => tim2(tim2_channel),
+    TIM5 => tim5(tim5_channel)
+);
+
+/// Everything one capture task needs to turn its timer's captures into a turret position
+pub(crate) struct Channel<M> {
+    /// which entry of the shared arrays this channel publishes to
+    index: usize,
+    monitor: M,
+    /// picks the timer's prescaler, and the frequencies a capture may have
+    range: AutoRange,
+    /// maps the turret's pulse width to its angle
+    calibration: Calibration,
+    /// smooths the pulse widths
+    filter: PulseFilter,
+}
+
+impl<M: Capture> Channel<M> {
+    pub(crate) fn new(
+        index: usize,
+        mut monitor: M,
+        range: AutoRange,
+        calibration: Calibration,
+        filter: PulseFilter,
+    ) -> Self {
+        monitor.flag_overflows();
+        Channel {
+            index,
+            monitor,
+            range,
+            calibration,
+            filter,
+        }
+    }
+
+    /// Handle a capture interrupt of this channel's timer
+    fn on_capture(
+        &mut self,
+        mut positions: impl Mutex<T = [TurretPosition; CHANNELS]>,
+        raw_positions: impl Mutex<T = [TurretPosition; CHANNELS]>,
+        mut watchdogs: impl Mutex<T = [Watchdog; CHANNELS]>,
+    ) {
+        // First, check that this interrupt is a valid capture. If not, bail out to speed up
+        // the interrupt.
+        let (period_clocks, pulse_clocks) = match self.monitor.capture() {
+            Some(capture) => capture,
+            None => return,
+        };
+
+        // A period that overflowed the counter reads back modulo 65536 ticks, possibly right in
+        // the band. The signal slowed down, so start over at the coarse prescaler.
+        // Otherwise, a period far from the expected one is a glitch, e.g. a spike cutting a
+        // period short, unless it stays that way and the timer needs a different prescaler.
+        let band = self.range.band();
+        let range = if self.monitor.overflowed() {
+            self.range.overflow()
+        } else {
+            self.range.capture(period_clocks)
+        };
+
+        // Give the signal as many periods as before to show up again at its new frequency
+        if self.range.band() != band {
+            let (index, lowest_hz) = (self.index, self.range.lowest_hz());
+            watchdogs.lock(|watchdogs| watchdogs[index].set_frequency(lowest_hz));
+        }
+
+        match range {
+            Range::InBand => {}
+            Range::Glitch => return,
+            Range::Reconfigure(prescaler) => {
+                self.monitor.set_prescaler(prescaler);
+                return;
+            }
+        }
+
+        // observe the pulse width, and map it to an angle.
+        // This is done up here to minimize time in the critical section.
+        let pulse_us = calibration::pulse_us(pulse_clocks, self.range.tick_hz());
+        let raw = self.calibration.angle(pulse_us);
+
+        // A pulse the turret cannot produce is noise rather than a position, so it does not
+        // keep the watchdog happy either.
+        if !raw.valid {
+            return;
+        }
+
+        // Samples from before the signal was lost have nothing to do with the current position
+        let index = self.index;
+        let lost =
+            positions.lock(|positions| matches!(positions[index], TurretPosition::Lost { .. }));
+        if lost {
+            self.filter.reset();
+        }
+        let filtered = self.calibration.angle(self.filter.update(pulse_us));
+        let now = now_ms();
+
+        // entering critical section
+        (positions, raw_positions, watchdogs).lock(|positions, raw_positions, watchdogs| {
+            // update the shared state
+            positions[index] = TurretPosition::Position(filtered.degrees);
+            raw_positions[index] = TurretPosition::Position(raw.degrees);
+            watchdogs[index].capture(now);
+        });
+        // leaving critical section
+    }
+}
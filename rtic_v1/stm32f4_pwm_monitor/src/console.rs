@@ -0,0 +1,45 @@
+use crate::app::{console, CONSOLE_PERIOD_MS};
+use pwm_monitor_core::calibration::Calibration;
+use rtic::mutex_prelude::*;
+use rtt_target::rprintln;
+use systick_monotonic::fugit::Duration;
+
+/// Longest command line, longer ones are dropped
+pub(crate) const LINE_LEN: usize = 16;
+
+/// Polls the RTT down channel for a new setpoint: a line with the angle in degrees, e.g. `-45`
+pub(crate) fn console(mut context: console::Context) {
+    console::spawn_after(Duration::<u64, 1, 1000>::from_ticks(
+        CONSOLE_PERIOD_MS.into(),
+    ))
+    .unwrap();
+
+    let mut buffer = [0u8; LINE_LEN];
+    let count = context.local.commands.read(&mut buffer);
+    for &byte in &buffer[..count] {
+        let line = &mut context.local.line;
+        if byte != b'\n' {
+            // Too long to be a number, keep dropping until the end of the line
+            if *context.local.line_len < LINE_LEN {
+                line[*context.local.line_len] = byte;
+            }
+            *context.local.line_len += 1;
+            continue;
+        }
+
+        let len = core::mem::take(context.local.line_len);
+        let setpoint = core::str::from_utf8(&line[..len.min(LINE_LEN)])
+            .ok()
+            .and_then(|line| line.trim().parse::<f32>().ok());
+        match setpoint {
+            Some(degrees) => {
+                let limits = Calibration::SERVO;
+                let degrees = degrees.clamp(limits.min_degrees, limits.max_degrees);
+                context.shared.setpoint.lock(|setpoint| *setpoint = degrees);
+                rprintln!("setpoint {}°", degrees);
+            }
+            None if len > LINE_LEN => rprintln!("line too long"),
+            None => rprintln!("expected an angle in degrees"),
+        }
+    }
+}
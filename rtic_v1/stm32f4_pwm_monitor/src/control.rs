@@ -0,0 +1,41 @@
+use crate::app::{control, CONTROLLED_TURRET, CONTROL_PERIOD_MS};
+use pwm_monitor_core::watchdog::TurretPosition;
+use rtic::mutex_prelude::*;
+use systick_monotonic::fugit::{Duration, TimerInstantU64};
+
+/// pulse width of the drive output at standstill, in µs
+pub(crate) const DRIVE_CENTER_US: f32 = 1500.0;
+
+/// pulse width change for full speed either way, in µs
+const DRIVE_RANGE_US: f32 = 500.0;
+
+/// Runs the PID loop of one turret every `CONTROL_PERIOD_MS`.
+/// The drive output is an ESC style pulse: `DRIVE_CENTER_US` stands still, and the controller's
+/// output of -1.0 to 1.0 moves it by up to `DRIVE_RANGE_US` either way.
+pub(crate) fn control(mut context: control::Context, scheduled: TimerInstantU64<1000>) {
+    // Scheduled from the previous run rather than from now, so the rate does not drift
+    let next = scheduled + Duration::<u64, 1, 1000>::from_ticks(CONTROL_PERIOD_MS.into());
+    control::spawn_at(next, next).unwrap();
+
+    let position = context
+        .shared
+        .last_observed_turret_positions
+        .lock(|positions| positions[CONTROLLED_TURRET]);
+    let setpoint = context.shared.setpoint.lock(|setpoint| *setpoint);
+
+    let pid = context.local.pid;
+    let output = match position {
+        TurretPosition::Position(degrees) => {
+            pid.update(setpoint, degrees, CONTROL_PERIOD_MS as f32 / 1000.0)
+        }
+        // Flying blind, stop. Start afresh once the position is back.
+        TurretPosition::Lost { .. } => {
+            pid.reset();
+            0.0
+        }
+    };
+
+    // The drive timer counts in µs
+    let pulse_us = DRIVE_CENTER_US + output * DRIVE_RANGE_US;
+    context.local.drive.set_duty(pulse_us as u16);
+}
@@ -5,73 +5,393 @@
 
 use panic_rtt_target as _panic_handler;
 
-/* declare a submodule for handling tim8 interrupts */
-mod tim8;
+/* declare a submodule for handling the capture interrupts */
+mod channel;
+/* and one for noticing when those interrupts stop coming */
+mod watchdog;
+/* the position control loop */
+mod control;
+/* and the RTT console that changes its setpoint */
+mod console;
+/* streaming the positions to a PC */
+mod telemetry;
+/* RC receivers with all channels on one line */
+mod rc;
+/* loopback test of the capture path */
+mod self_test;
 
 /* declare the RTIC application itself */
-#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true)]
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [SPI1, SPI2])]
 mod app {
 
     /* bring dependencies into scope */
-    use rtt_target::{rprintln, rtt_init_print};
-    use stm32f4xx_hal::{pac::TIM8, prelude::*, timer::PwmInput, timer::Timer};
+    use pwm_monitor_core::calibration::{self, Calibration};
+    use pwm_monitor_core::filter::Filter;
+    use pwm_monitor_core::pid::{Gains, Pid};
+    use pwm_monitor_core::range::AutoRange;
+    use pwm_monitor_core::rc::{Ppm, Receiver, Sbus};
+    use pwm_monitor_core::watchdog::{TurretPosition, Watchdog};
+
+    use crate::channel::{Capture, Channel};
+    use rtt_target::{rprintln, rtt_init_default, set_print_channel, DownChannel};
+    use stm32f4xx_hal::{
+        pac::{TIM1, TIM11, TIM12, TIM2, TIM3, TIM4, TIM5, TIM8, USART1, USART2},
+        prelude::*,
+        serial::{config::StopBits, Config, Rx, Tx},
+        timer::PwmInput,
+        timer::{Channel1, PwmChannel, Timer},
+    };
+    use systick_monotonic::{
+        fugit::{Duration, TimerInstantU64},
+        Systick,
+    };
     /// PWM input monitor type
-    pub(crate) type PwmMonitor = PwmInput<TIM8>;
+    pub(crate) type PwmMonitor<TIM> = Channel<PwmInput<TIM>>;
+
+    /// how many turrets are monitored, one per timer:
+    /// 0 on TIM8 CH1 (PC6), 1 on TIM1 CH1 (PA8), 2 on TIM3 CH1 (PA6), 3 on TIM4 CH1 (PB6),
+    /// 4 on TIM2 CH1 (PA15) and 5 on TIM5 CH1 (PA0).
+    pub(crate) const CHANNELS: usize = 6;
+
+    /// lowest turret PWM frequency expected. The timers start out with room for a period this
+    /// long, and auto-range to the actual frequency from there.
+    const LOWEST_FREQUENCY_HZ: u32 = 20;
+
+    /// how many periods may go by without a valid capture before the signal counts as lost
+    const LOST_AFTER_PERIODS: u32 = 5;
+
+    /// captures whose frequency is further than this fraction from the measured one are
+    /// glitches, or once they keep coming, a reason to re-range
+    const FREQUENCY_TOLERANCE: f32 = 0.2;
+
+    /// how many pulse widths the median filter looks at
+    const MEDIAN_WINDOW: usize = 5;
+
+    /// weight of each new median in the moving average, 1.0 turns the average off
+    const AVERAGE_WEIGHT: f32 = 0.25;
+
+    /// pulse width filter of a turret position
+    pub(crate) type PulseFilter = Filter<MEDIAN_WINDOW>;
+
+    /// the turret the control loop moves, driven by TIM11 CH1 (PB9)
+    pub(crate) const CONTROLLED_TURRET: usize = 0;
+
+    /// how often the control loop runs
+    pub(crate) const CONTROL_PERIOD_MS: u32 = 10;
+
+    /// PID gains of the control loop, the output is -1.0 to 1.0 of full speed
+    const GAINS: Gains = Gains {
+        kp: 0.02,
+        ki: 0.01,
+        kd: 0.001,
+    };
+
+    /// how often the console looks for a new setpoint
+    pub(crate) const CONSOLE_PERIOD_MS: u32 = 50;
+
+    /// how often the positions go out over USART2 (PA2, the ST-LINK's virtual COM port)
+    pub(crate) const TELEMETRY_PERIOD_MS: u32 = 20;
+
+    /// fast enough for a frame per turret well within `TELEMETRY_PERIOD_MS`
+    const TELEMETRY_BAUD: u32 = 115_200;
+
+    /// frame rate of a PPM receiver, the slower of the two kinds
+    const RC_FRAME_HZ: u32 = 44;
 
     /* resources shared across RTIC tasks */
     #[shared]
     struct Shared {
-        /// the last observed position of the turret
-        last_observed_turret_position: f32,
+        /// the last observed position of each turret after filtering, or since when it is unknown
+        last_observed_turret_positions: [TurretPosition; CHANNELS],
+        /// the same, straight from the latest captures
+        raw_turret_positions: [TurretPosition; CHANNELS],
+        /// when the last valid capture of each turret happened
+        watchdogs: [Watchdog; CHANNELS],
+        /// where the control loop moves its turret to, in degrees
+        setpoint: f32,
+        /// channels of the PPM receiver on TIM12 CH1 (PB14)
+        ppm_receiver: Receiver,
+        /// channels of the SBUS receiver on USART1 RX (PA10), behind an inverter
+        sbus_receiver: Receiver,
     }
 
     /* resources local to specific RTIC tasks */
     #[local]
     struct Local {
-        monitor: PwmMonitor,
+        tim8_channel: PwmMonitor<TIM8>,
+        tim1_channel: PwmMonitor<TIM1>,
+        tim3_channel: PwmMonitor<TIM3>,
+        tim4_channel: PwmMonitor<TIM4>,
+        tim2_channel: PwmMonitor<TIM2>,
+        tim5_channel: PwmMonitor<TIM5>,
+        /// the control loop's state
+        pid: Pid,
+        /// the control loop's output, counting in µs
+        drive: PwmChannel<TIM11, 0>,
+        /// where setpoints come in
+        commands: DownChannel,
+        /// command line received so far, and its length including what did not fit
+        line: [u8; crate::console::LINE_LEN],
+        line_len: usize,
+        /// where the telemetry goes
+        serial: Tx<USART2>,
+        /// timestamps the PPM pulses
+        ppm_timer: TIM12,
+        /// timestamp of the previous PPM pulse
+        last_edge: u16,
+        /// how often the PPM timer wrapped around since then
+        ppm_wraps: u8,
+        ppm_decoder: Ppm,
+        /// where the SBUS bytes come in
+        sbus_rx: Rx<USART1>,
+        sbus_decoder: Sbus,
     }
 
+    /// millisecond timer for the watchdog
+    #[monotonic(binds = SysTick, default = true)]
+    type MonoTimer = Systick<1000>;
+
     #[init]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
-        // Enable RTT logging
-        rtt_init_print!();
+        // Enable RTT logging, and take commands from the host
+        let channels = rtt_init_default!();
+        set_print_channel(channels.up.0);
         rprintln!("hello, world!");
         // retrieve the RCC register, which is needed to obtain a handle to the clocks
         let rcc = ctx.device.RCC.constrain();
         // then retreive the clocks, so we can configure timers later on
         let clocks = rcc.cfgr.freeze();
+        // SysTick delays the self-test, before it becomes the monotonic
+        let mut delay = ctx.core.SYST.delay(&clocks);
 
-        // obtain a reference to the GPIOC register block, so we can configure pins on the PC bus.
+        // obtain references to the GPIO register blocks, so we can configure pins on them.
+        let gpioa = ctx.device.GPIOA.split();
+        let gpiob = ctx.device.GPIOB.split();
         let gpioc = ctx.device.GPIOC.split();
 
-        // Configure one of TIM8's CH1 pins, so that its attached to the peripheral.
+        // Configure one of each timer's CH1 pins, so that its attached to the peripheral.
         // We need to do this since the pins are multiplexed across multiple peripherals
         let tim8_cc1 = gpioc.pc6.into_alternate();
+        let tim1_cc1 = gpioa.pa8.into_alternate();
+        let tim3_cc1 = gpioa.pa6.into_alternate();
+        let tim4_cc1 = gpiob.pb6.into_alternate();
+        let tim2_cc1 = gpioa.pa15.into_alternate();
+        let tim5_cc1 = gpioa.pa0.into_alternate();
+
+        // Configure the timers into PWM input mode.
+        // This requires a "best guess" of the input frequency in order to be accurate. The
+        // lowest frequency gives a coarse prescaler, and the channels pick a better one once
+        // they measured the actual frequency.
+        // Note: as a side-effect each timer's capture interrupt is enabled and fires whenever a
+        //      capture-compare cycle is complete. See the reference manual's paragraphs on PWM
+        //      Input.
+        // TIM1 and TIM8 hang off APB2, TIM2 to TIM5 off APB1, which may run slower.
+        let apb1_range = AutoRange::new(
+            clocks.timclk1().raw(),
+            LOWEST_FREQUENCY_HZ,
+            FREQUENCY_TOLERANCE,
+        );
+        let apb2_range = AutoRange::new(
+            clocks.timclk2().raw(),
+            LOWEST_FREQUENCY_HZ,
+            FREQUENCY_TOLERANCE,
+        );
+        // every channel watches the same kind of turret
+        fn channel<M: Capture>(index: usize, monitor: M, range: AutoRange) -> Channel<M> {
+            Channel::new(
+                index,
+                monitor,
+                range,
+                Calibration::SERVO,
+                PulseFilter::new(AVERAGE_WEIGHT),
+            )
+        }
+        let tim8_monitor =
+            Timer::new(ctx.device.TIM8, &clocks).pwm_input(LOWEST_FREQUENCY_HZ.Hz(), tim8_cc1);
+
+        // Holding the user button (B1, PC13) through reset runs the self-test, which needs PB8
+        // jumpered to PC6 instead of a turret. Normal operation carries on after the report, with
+        // the user LED (LD2, PA5) lit if the test failed.
+        if gpioc.pc13.into_input().is_low() {
+            let mut generator = ctx
+                .device
+                .TIM10
+                .pwm_hz(
+                    Channel1::new(gpiob.pb8),
+                    crate::self_test::FREQUENCY_HZ.Hz(),
+                    &clocks,
+                )
+                .split();
+            if !crate::self_test::run(&tim8_monitor, &mut generator, &mut delay) {
+                // The pin keeps driving the LED after it is dropped
+                gpioa.pa5.into_push_pull_output().set_high();
+            }
+        }
+        let mono = Systick::new(delay.release().release(), clocks.sysclk().raw());
 
-        // Configure TIM8 into PWM input mode.
-        // This requires a "best guess" of the input frequency in order to be accurate.
-        // Note: as a side-effect TIM8's interrupt is enabled and fires whenever a capture-compare
-        //      cycle is complete. See the reference manual's paragraphs on PWM Input.
-        let monitor = Timer::new(ctx.device.TIM8, &clocks).pwm_input(240.Hz(), tim8_cc1);
+        let tim8_channel = channel(0, tim8_monitor, apb2_range);
+        let tim1_channel = channel(
+            1,
+            Timer::new(ctx.device.TIM1, &clocks).pwm_input(LOWEST_FREQUENCY_HZ.Hz(), tim1_cc1),
+            apb2_range,
+        );
+        let tim3_channel = channel(
+            2,
+            Timer::new(ctx.device.TIM3, &clocks).pwm_input(LOWEST_FREQUENCY_HZ.Hz(), tim3_cc1),
+            apb1_range,
+        );
+        let tim4_channel = channel(
+            3,
+            Timer::new(ctx.device.TIM4, &clocks).pwm_input(LOWEST_FREQUENCY_HZ.Hz(), tim4_cc1),
+            apb1_range,
+        );
+        let tim2_channel = channel(
+            4,
+            Timer::new(ctx.device.TIM2, &clocks).pwm_input(LOWEST_FREQUENCY_HZ.Hz(), tim2_cc1),
+            apb1_range,
+        );
+        let tim5_channel = channel(
+            5,
+            Timer::new(ctx.device.TIM5, &clocks).pwm_input(LOWEST_FREQUENCY_HZ.Hz(), tim5_cc1),
+            apb1_range,
+        );
+
+        // The drive output: 20 ms periods for an ESC or continuous rotation servo, at 1 MHz so
+        // that the duty cycle is in µs. The capture timers are all taken, TIM11 has no use
+        // for more than one channel.
+        let mut drive = ctx
+            .device
+            .TIM11
+            .pwm_us(Channel1::new(gpiob.pb9), 20.millis(), &clocks)
+            .split();
+        drive.set_duty(crate::control::DRIVE_CENTER_US as u16);
+        drive.enable();
+
+        // Start checking for lost signals. Until the first capture comes in, a signal
+        // counts as lost since boot. Not knowing the frequency, allow for the lowest, until
+        // each channel's capture task ranges to its signal.
+        let watchdog = Watchdog::new(LOST_AFTER_PERIODS, LOWEST_FREQUENCY_HZ, 0);
+        signal_watchdog::spawn_after(Duration::<u64, 1, 1000>::from_ticks(
+            watchdog.timeout_ms().into(),
+        ))
+        .unwrap();
+
+        // Start the control loop, and the console feeding it
+        let now = monotonics::now();
+        control::spawn_at(now, now).unwrap();
+        console::spawn().unwrap();
+
+        // Stream the positions to the host
+        let serial = ctx
+            .device
+            .USART2
+            .tx(gpioa.pa2, TELEMETRY_BAUD.bps(), &clocks)
+            .unwrap();
+        telemetry::spawn().unwrap();
+
+        // RC receivers, next to the PWM inputs: PPM with input capture on TIM12 CH1, and
+        // SBUS on USART1 at 100 kBd 8E2. The STM32F4's USART cannot invert its input, SBUS
+        // needs an external inverter.
+        let _ppm_pin = gpiob.pb14.into_alternate::<9>();
+        let ppm_timer = Timer::new(ctx.device.TIM12, &clocks).release();
+        crate::rc::ppm_capture(&ppm_timer, clocks.timclk1().raw());
+        let sbus_config = Config::default()
+            .baudrate(100_000.bps())
+            .wordlength_9()
+            .parity_even()
+            .stopbits(StopBits::STOP2);
+        let mut sbus_rx = ctx
+            .device
+            .USART1
+            .rx(gpioa.pa10, sbus_config, &clocks)
+            .unwrap();
+        sbus_rx.listen();
+        let receiver = Receiver::new(Watchdog::new(LOST_AFTER_PERIODS, RC_FRAME_HZ, 0));
 
         // lastly return the shared and local resources, as per RTIC's spec.
         (
             Shared {
-                last_observed_turret_position: 0.0,
+                last_observed_turret_positions: [TurretPosition::Lost { since: 0 }; CHANNELS],
+                raw_turret_positions: [TurretPosition::Lost { since: 0 }; CHANNELS],
+                watchdogs: [watchdog; CHANNELS],
+                setpoint: 0.0,
+                ppm_receiver: receiver,
+                sbus_receiver: receiver,
             },
-            Local { monitor },
-            init::Monotonics(),
+            Local {
+                tim8_channel,
+                tim1_channel,
+                tim3_channel,
+                tim4_channel,
+                tim2_channel,
+                tim5_channel,
+                pid: Pid::new(GAINS, -1.0, 1.0),
+                drive,
+                commands: channels.down.0,
+                line: [0; crate::console::LINE_LEN],
+                line_len: 0,
+                serial,
+                ppm_timer,
+                last_edge: 0,
+                ppm_wraps: 0,
+                ppm_decoder: Ppm::new(),
+                sbus_rx,
+                sbus_decoder: Sbus::new(),
+            },
+            init::Monotonics(mono),
         )
     }
 
-    /* bring tim8's interrupt handler into scope */
-    use crate::tim8::tim8_cc;
+    /* bring the capture interrupt handlers into scope */
+    use crate::channel::{tim1_cc, tim2, tim3, tim4, tim5, tim8_cc};
+    use crate::rc::{tim12, usart1};
+    /* as well as the software tasks */
+    use crate::console::console;
+    use crate::control::control;
+    use crate::telemetry::telemetry;
+    use crate::watchdog::signal_watchdog;
 
+    // The capture, watchdog and control tasks run at priority 2, the console and the blocking
+    // telemetry below them at 1.
     // RTIC docs specify we can modularize the code by using these `extern` blocks.
     // This allows us to specify the tasks in other modules and still work within
     // RTIC's infrastructure.
     extern "Rust" {
-        #[task(binds=TIM8_CC, local=[monitor], shared=[last_observed_turret_position])]
+        #[task(binds=TIM8_CC, priority=2, local=[tim8_channel], shared=[last_observed_turret_positions, raw_turret_positions, watchdogs])]
         fn tim8_cc(context: tim8_cc::Context);
+
+        #[task(binds=TIM1_CC, priority=2, local=[tim1_channel], shared=[last_observed_turret_positions, raw_turret_positions, watchdogs])]
+        fn tim1_cc(context: tim1_cc::Context);
+
+        #[task(binds=TIM3, priority=2, local=[tim3_channel], shared=[last_observed_turret_positions, raw_turret_positions, watchdogs])]
+        fn tim3(context: tim3::Context);
+
+        #[task(binds=TIM4, priority=2, local=[tim4_channel], shared=[last_observed_turret_positions, raw_turret_positions, watchdogs])]
+        fn tim4(context: tim4::Context);
+
+        #[task(binds=TIM2, priority=2, local=[tim2_channel], shared=[last_observed_turret_positions, raw_turret_positions, watchdogs])]
+        fn tim2(context: tim2::Context);
+
+        #[task(binds=TIM5, priority=2, local=[tim5_channel], shared=[last_observed_turret_positions, raw_turret_positions, watchdogs])]
+        fn tim5(context: tim5::Context);
+
+        // TIM12 shares its interrupt with TIM8's break, which is not used
+        #[task(binds=TIM8_BRK_TIM12, priority=2, local=[ppm_timer, last_edge, ppm_wraps, ppm_decoder], shared=[ppm_receiver])]
+        fn tim12(context: tim12::Context);
+
+        #[task(binds=USART1, priority=2, local=[sbus_rx, sbus_decoder], shared=[sbus_receiver])]
+        fn usart1(context: usart1::Context);
+
+        #[task(priority=2, shared=[last_observed_turret_positions, raw_turret_positions, watchdogs, ppm_receiver, sbus_receiver])]
+        fn signal_watchdog(context: signal_watchdog::Context);
+
+        #[task(priority=2, local=[pid, drive], shared=[last_observed_turret_positions, setpoint])]
+        fn control(context: control::Context, scheduled: TimerInstantU64<1000>);
+
+        #[task(local=[commands, line, line_len], shared=[setpoint])]
+        fn console(context: console::Context);
+
+        #[task(local=[serial], shared=[last_observed_turret_positions, raw_turret_positions])]
+        fn telemetry(context: telemetry::Context);
     }
 }
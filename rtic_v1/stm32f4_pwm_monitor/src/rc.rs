@@ -0,0 +1,101 @@
+use crate::app::{tim12, usart1};
+use crate::watchdog::now_ms;
+use pwm_monitor_core::calibration::Calibration;
+use pwm_monitor_core::rc::{Frame, Receiver};
+use rtic::mutex_prelude::*;
+use stm32f4xx_hal::nb;
+use stm32f4xx_hal::pac::TIM12;
+use stm32f4xx_hal::prelude::*;
+
+/// rate the PPM timer counts at, so captures are in µs
+pub(crate) const PPM_TICK_HZ: u32 = 1_000_000;
+
+/// Set up TIM12 to timestamp the rising edges on CH1 (PB14) in µs.
+/// The HAL has no input capture mode, so this goes to the registers. TIM12 counts in 16 bits,
+/// so it also interrupts when it wraps around, which tells a 2 ms gap from a 67.5 ms one.
+#[allow(unsafe_code)]
+pub(crate) fn ppm_capture(tim12: &TIM12, timer_clock_hz: u32) {
+    tim12
+        .psc
+        .write(|w| w.psc().bits((timer_clock_hz / PPM_TICK_HZ - 1) as u16));
+    // Safety: any value is a valid reload
+    tim12.arr.write(|w| unsafe { w.arr().bits(u16::MAX) });
+    // load the prescaler, without flagging that as a wrap
+    tim12.cr1.modify(|_, w| w.urs().set_bit());
+    tim12.egr.write(|w| w.ug().update());
+    // CC1 captures TI1. Safety: 0b01 is a valid CC1S value, the PAC just has no name for it.
+    tim12
+        .ccmr1_input()
+        .modify(|_, w| unsafe { w.cc1s().bits(0b01) });
+    tim12
+        .ccer
+        .modify(|_, w| w.cc1p().clear_bit().cc1np().clear_bit().cc1e().set_bit());
+    tim12
+        .dier
+        .modify(|_, w| w.cc1ie().set_bit().uie().set_bit());
+    tim12.cr1.modify(|_, w| w.cen().set_bit());
+}
+
+/// Publishes a decoded frame of an RC receiver
+fn publish(receiver: &mut impl Mutex<T = Receiver>, frame: &Frame) {
+    let now = now_ms();
+    receiver.lock(|receiver| receiver.frame(frame, &Calibration::SERVO, now));
+}
+
+/// A rising edge of the PPM signal, or the PPM timer wrapping around
+#[allow(unsafe_code)]
+pub(crate) fn tim12(mut context: tim12::Context) {
+    let timer = context.local.ppm_timer;
+    let status = timer.sr.read();
+    let wrapped = status.uif().bit_is_set();
+    if wrapped {
+        // The other flags are cleared by writing 0, so leave them at 1.
+        // Safety: all bits of SR are flags
+        timer.sr.write(|w| unsafe { w.bits(0xffff & !1) });
+    }
+    if status.cc1if().bit_is_clear() {
+        let wraps = context.local.ppm_wraps;
+        *wraps = wraps.saturating_add(wrapped.into());
+        return;
+    }
+
+    // reading the capture clears its flag
+    let edge = timer.ccr1().read().ccr().bits();
+    // A wrap flagged along with the edge came first if the edge is early in the count. The
+    // interrupt is never half a wrap late.
+    let wrapped_first = wrapped && edge < 0x8000;
+    let wraps = core::mem::replace(context.local.ppm_wraps, (wrapped && !wrapped_first).into())
+        .saturating_add(wrapped_first.into());
+    let last_edge = core::mem::replace(context.local.last_edge, edge);
+    // The wraps stop counting at 255, which is still well beyond a sync gap
+    let interval_us = (u32::from(wraps) << 16)
+        .wrapping_add(edge.into())
+        .wrapping_sub(last_edge.into());
+
+    if let Some(frame) = context.local.ppm_decoder.interval(interval_us as f32) {
+        publish(&mut context.shared.ppm_receiver, &frame);
+    }
+}
+
+/// A byte of the SBUS stream
+pub(crate) fn usart1(mut context: usart1::Context) {
+    let decoder = context.local.sbus_decoder;
+    loop {
+        match context.local.sbus_rx.read() {
+            Ok(byte) => match decoder.byte(byte) {
+                Some(frame) if frame.failsafe => {
+                    let now = now_ms();
+                    context
+                        .shared
+                        .sbus_receiver
+                        .lock(|receiver| receiver.failsafe(now));
+                }
+                Some(frame) => publish(&mut context.shared.sbus_receiver, &frame.frame),
+                None => {}
+            },
+            Err(nb::Error::WouldBlock) => break,
+            // A framing, parity or overrun error leaves the frame in pieces
+            Err(nb::Error::Other(_)) => decoder.reset(),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use rtt_target::rprintln;
+use stm32f4xx_hal::pac::{TIM10, TIM8};
+use stm32f4xx_hal::prelude::*;
+use stm32f4xx_hal::timer::{PwmChannel, PwmInput, SysDelay};
+
+/// frequency of the generated waveform
+pub(crate) const FREQUENCY_HZ: u32 = 240;
+
+/// duty cycles the self-test sweeps through, in percent
+const SWEEP_PERCENT: [f32; 7] = [5.0, 10.0, 25.0, 50.0, 75.0, 90.0, 95.0];
+
+/// how far a measured duty cycle may be off, in percentage points
+const TOLERANCE_PERCENT: f32 = 0.5;
+
+/// how often to look again when a capture is not valid yet
+const ATTEMPTS: u32 = 3;
+
+/// Loopback test of the capture path: TIM10 CH1 (PB8) generates a known waveform, jumpered to
+/// TIM8 CH1 (PC6), which measures it. Runs from `init`, before the capture interrupt is enabled,
+/// and prints a report. Returns whether all duty cycles were measured within the tolerance.
+pub(crate) fn run(
+    monitor: &PwmInput<TIM8>,
+    generator: &mut PwmChannel<TIM10, 0>,
+    delay: &mut SysDelay,
+) -> bool {
+    let period_us = 1_000_000 / FREQUENCY_HZ;
+    rprintln!("self-test: PB8 should be jumpered to PC6");
+
+    let max_duty = generator.get_max_duty() as f32;
+    generator.enable();
+    let mut passed = 0;
+    for expected in SWEEP_PERCENT {
+        generator.set_duty((max_duty * expected / 100.0) as u16);
+        // The new duty cycle starts with the next period, and needs a whole one to be captured
+        delay.delay((3 * period_us).micros());
+
+        let mut measured = None;
+        for _ in 0..ATTEMPTS {
+            if monitor.is_valid_capture() {
+                measured = Some(monitor.get_duty_cycle());
+                break;
+            }
+            delay.delay(period_us.micros());
+        }
+
+        match measured {
+            Some(measured)
+                if measured > expected - TOLERANCE_PERCENT
+                    && measured < expected + TOLERANCE_PERCENT =>
+            {
+                passed += 1;
+                rprintln!("self-test: {}% measured as {}%: ok", expected, measured);
+            }
+            Some(measured) => {
+                rprintln!("self-test: {}% measured as {}%: FAIL", expected, measured);
+            }
+            None => rprintln!("self-test: {}% not captured: FAIL", expected),
+        }
+    }
+    generator.disable();
+
+    let pass = passed == SWEEP_PERCENT.len();
+    rprintln!(
+        "self-test: {} of {} duty cycles within {}%: {}",
+        passed,
+        SWEEP_PERCENT.len(),
+        TOLERANCE_PERCENT,
+        if pass { "PASS" } else { "FAIL" }
+    );
+    pass
+}
@@ -0,0 +1,36 @@
+use crate::app::{telemetry, CHANNELS, TELEMETRY_PERIOD_MS};
+use crate::watchdog::now_ms;
+use pwm_monitor_core::telemetry::Sample;
+use rtic::mutex_prelude::*;
+use stm32f4xx_hal::prelude::*;
+use systick_monotonic::fugit::Duration;
+
+/// Sends a frame per turret every `TELEMETRY_PERIOD_MS`, see `pwm_monitor_core::telemetry`.
+/// The writes block, which is why this runs below everything else.
+pub(crate) fn telemetry(mut context: telemetry::Context) {
+    telemetry::spawn_after(Duration::<u64, 1, 1000>::from_ticks(
+        TELEMETRY_PERIOD_MS.into(),
+    ))
+    .unwrap();
+
+    let timestamp_ms = now_ms();
+    let filtered = context
+        .shared
+        .last_observed_turret_positions
+        .lock(|positions| *positions);
+    let raw = context
+        .shared
+        .raw_turret_positions
+        .lock(|positions| *positions);
+
+    for turret in 0..CHANNELS {
+        let sample = Sample {
+            timestamp_ms,
+            turret: turret as u8,
+            filtered: filtered[turret],
+            raw: raw[turret],
+        };
+        // Nothing to be done about a lost byte, the host drops the frame
+        context.local.serial.bwrite_all(&sample.to_frame()).ok();
+    }
+}
@@ -0,0 +1,51 @@
+use crate::app::{monotonics, signal_watchdog};
+use pwm_monitor_core::watchdog::{TurretPosition, Watchdog};
+use rtic::mutex_prelude::*;
+use rtt_target::rprintln;
+use systick_monotonic::fugit::Duration;
+
+/// Current time in ms, wrapping around like the watchdog expects
+pub(crate) fn now_ms() -> u32 {
+    monotonics::now().ticks() as u32
+}
+
+/// Periodically flags turret positions as lost when captures stopped coming in.
+/// Runs once per shortest watchdog timeout, so a lost signal is noticed within two of its own.
+pub(crate) fn signal_watchdog(mut context: signal_watchdog::Context) {
+    let now = now_ms();
+
+    let timeout_ms = (
+        &mut context.shared.last_observed_turret_positions,
+        &mut context.shared.raw_turret_positions,
+        &mut context.shared.watchdogs,
+    )
+        .lock(|positions, raw_positions, watchdogs| {
+            for (index, watchdog) in watchdogs.iter().enumerate() {
+                if let Some(since) = watchdog.lost_since(now) {
+                    if let TurretPosition::Position(_) = positions[index] {
+                        rprintln!("turret {} signal lost", index);
+                    }
+                    positions[index] = TurretPosition::Lost { since };
+                    raw_positions[index] = TurretPosition::Lost { since };
+                }
+            }
+            // Each channel times out after a few periods of its own frequency
+            watchdogs
+                .iter()
+                .map(Watchdog::timeout_ms)
+                .min()
+                .unwrap_or(u32::MAX)
+        });
+
+    // The RC receivers flag their own channels
+    context
+        .shared
+        .ppm_receiver
+        .lock(|receiver| receiver.check(now));
+    context
+        .shared
+        .sbus_receiver
+        .lock(|receiver| receiver.check(now));
+
+    signal_watchdog::spawn_after(Duration::<u64, 1, 1000>::from_ticks(timeout_ms.into())).unwrap();
+}
@@ -0,0 +1,191 @@
+//! Turning a PWM capture into a turret angle.
+//!
+//! The timer captures the period and the high time of the signal in timer clocks. Together with
+//! the timer's tick rate that gives the pulse width in µs, which a servo style [`Calibration`]
+//! maps to an angle in degrees.
+
+/// Timer tick rate in PWM input mode. Mirrors the prescaler `Timer::pwm_input` picks for its best
+/// guess of the input frequency, which stm32f4xx-hal does not expose.
+pub fn tick_hz(timer_clock_hz: u32, best_guess_hz: u32) -> u32 {
+    let psc = (timer_clock_hz / best_guess_hz - 1) / (1 << 16);
+    timer_clock_hz / (psc + 1)
+}
+
+/// Frequency of the signal in Hz, from its period in timer clocks
+pub fn frequency_hz(period_clocks: u32, tick_hz: u32) -> f32 {
+    if period_clocks == 0 {
+        return 0.0;
+    }
+    tick_hz as f32 / period_clocks as f32
+}
+
+/// Pulse width (high time) of the signal in µs, from timer clocks
+pub fn pulse_us(pulse_clocks: u32, tick_hz: u32) -> f32 {
+    pulse_clocks as f32 * 1_000_000.0 / tick_hz as f32
+}
+
+/// An angle worked out from a pulse width
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Angle {
+    /// Clamped to the calibrated range
+    pub degrees: f32,
+    /// The pulse width was within the calibrated range, give or take the tolerance.
+    /// An invalid angle is only the nearest end of the range, e.g. from a glitch or a miswired
+    /// signal.
+    pub valid: bool,
+}
+
+/// Servo style mapping from pulse width to angle: linear from `min_pulse_us` to
+/// `center_pulse_us`, and from there to `max_pulse_us`, so an off-center neutral position
+/// still maps to the middle of the angle range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    /// Pulse width at `min_degrees`
+    pub min_pulse_us: f32,
+    /// Pulse width halfway between `min_degrees` and `max_degrees`
+    pub center_pulse_us: f32,
+    /// Pulse width at `max_degrees`
+    pub max_pulse_us: f32,
+    pub min_degrees: f32,
+    pub max_degrees: f32,
+    /// How far a pulse width may be outside `min_pulse_us..=max_pulse_us` and still be valid
+    pub tolerance_us: f32,
+}
+
+impl Calibration {
+    /// A typical hobby servo: 1000 µs to 2000 µs for -90° to 90°
+    pub const SERVO: Calibration = Calibration {
+        min_pulse_us: 1000.0,
+        center_pulse_us: 1500.0,
+        max_pulse_us: 2000.0,
+        min_degrees: -90.0,
+        max_degrees: 90.0,
+        tolerance_us: 50.0,
+    };
+
+    /// Map a pulse width to an angle, clamped to the calibrated range
+    pub fn angle(&self, pulse_us: f32) -> Angle {
+        let valid = pulse_us >= self.min_pulse_us - self.tolerance_us
+            && pulse_us <= self.max_pulse_us + self.tolerance_us;
+
+        let center_degrees = (self.min_degrees + self.max_degrees) / 2.0;
+        let degrees = if pulse_us <= self.min_pulse_us {
+            self.min_degrees
+        } else if pulse_us >= self.max_pulse_us {
+            self.max_degrees
+        } else if pulse_us < self.center_pulse_us {
+            interpolate(
+                pulse_us,
+                (self.min_pulse_us, self.min_degrees),
+                (self.center_pulse_us, center_degrees),
+            )
+        } else {
+            interpolate(
+                pulse_us,
+                (self.center_pulse_us, center_degrees),
+                (self.max_pulse_us, self.max_degrees),
+            )
+        };
+
+        Angle { degrees, valid }
+    }
+}
+
+/// Linear interpolation at `x` between two points
+fn interpolate(x: f32, (x0, y0): (f32, f32), (x1, y1): (f32, f32)) -> f32 {
+    y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn timer_resolution() {
+        // 16 MHz HSI, 240 Hz best guess: prescaler 1, 8 MHz
+        assert_eq!(tick_hz(16_000_000, 240), 8_000_000);
+        // Fast enough to need no prescaler at all
+        assert_eq!(tick_hz(16_000_000, 1000), 16_000_000);
+
+        assert_close(frequency_hz(33_333, 8_000_000), 240.002);
+        assert_eq!(frequency_hz(0, 8_000_000), 0.0);
+        assert_close(pulse_us(12_000, 8_000_000), 1500.0);
+    }
+
+    #[test]
+    fn servo_angles() {
+        let servo = Calibration::SERVO;
+        for (pulse, degrees) in [
+            (1000.0, -90.0),
+            (1250.0, -45.0),
+            (1500.0, 0.0),
+            (1750.0, 45.0),
+            (2000.0, 90.0),
+        ] {
+            assert_eq!(
+                servo.angle(pulse),
+                Angle {
+                    degrees,
+                    valid: true
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn off_center_neutral() {
+        let calibration = Calibration {
+            center_pulse_us: 1400.0,
+            min_degrees: 0.0,
+            max_degrees: 180.0,
+            ..Calibration::SERVO
+        };
+        assert_close(calibration.angle(1400.0).degrees, 90.0);
+        assert_close(calibration.angle(1200.0).degrees, 45.0);
+        assert_close(calibration.angle(1700.0).degrees, 135.0);
+    }
+
+    #[test]
+    fn clamps_and_flags() {
+        let servo = Calibration::SERVO;
+        // Slightly out of range: clamped but still valid
+        assert_eq!(
+            servo.angle(980.0),
+            Angle {
+                degrees: -90.0,
+                valid: true
+            }
+        );
+        assert_eq!(
+            servo.angle(2040.0),
+            Angle {
+                degrees: 90.0,
+                valid: true
+            }
+        );
+        // Way out: clamped and invalid
+        assert_eq!(
+            servo.angle(500.0),
+            Angle {
+                degrees: -90.0,
+                valid: false
+            }
+        );
+        assert_eq!(
+            servo.angle(3000.0),
+            Angle {
+                degrees: 90.0,
+                valid: false
+            }
+        );
+    }
+}
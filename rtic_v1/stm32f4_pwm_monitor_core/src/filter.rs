@@ -0,0 +1,136 @@
+//! Noise filtering of the captured pulse widths.
+//!
+//! Electrical noise makes the captures jitter, and now and then spike. A capture whose period is
+//! outside the expected [`Band`] is a glitch and dropped outright. The rest go through a
+//! [`Filter`]: the median of the last `N` samples removes the odd spike, and an exponential
+//! moving average on top of that smooths the jitter.
+
+/// Range of signal frequencies a capture may have, anything else is a glitch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub min_hz: f32,
+    pub max_hz: f32,
+}
+
+impl Band {
+    /// `nominal_hz`, give or take a `tolerance` fraction of it, e.g. 0.2 for ±20 %
+    pub fn around(nominal_hz: f32, tolerance: f32) -> Self {
+        Band {
+            min_hz: nominal_hz * (1.0 - tolerance),
+            max_hz: nominal_hz * (1.0 + tolerance),
+        }
+    }
+
+    pub fn contains(&self, frequency_hz: f32) -> bool {
+        (self.min_hz..=self.max_hz).contains(&frequency_hz)
+    }
+}
+
+/// Median of the last `N` samples, followed by an exponential moving average
+#[derive(Clone, Copy, Debug)]
+pub struct Filter<const N: usize> {
+    /// Ring buffer of the latest samples, the first `len` of them valid
+    window: [f32; N],
+    len: usize,
+    next: usize,
+    /// Weight of a new median in the average, 1 to turn the average off
+    alpha: f32,
+    average: Option<f32>,
+}
+
+impl<const N: usize> Filter<N> {
+    /// A filter whose average takes each new median in with a weight of `alpha`, in `0.0..=1.0`
+    pub const fn new(alpha: f32) -> Self {
+        assert!(N > 0, "the median needs at least one sample");
+        Filter {
+            window: [0.0; N],
+            len: 0,
+            next: 0,
+            alpha,
+            average: None,
+        }
+    }
+
+    /// Forget all samples, e.g. after the signal was lost, so the filter does not drag the
+    /// old value into the new one
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+        self.average = None;
+    }
+
+    /// Take in a new sample, and return the filtered value
+    pub fn update(&mut self, sample: f32) -> f32 {
+        self.window[self.next] = sample;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        let median = self.median();
+        let average = match self.average {
+            Some(average) => average + self.alpha * (median - average),
+            // Start from the first median rather than from 0
+            None => median,
+        };
+        self.average = Some(average);
+        average
+    }
+
+    /// Median of the samples so far, the mean of the middle two for an even number of them
+    fn median(&self) -> f32 {
+        let mut sorted = self.window;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by(f32::total_cmp);
+        let middle = self.len / 2;
+        if self.len % 2 == 1 {
+            sorted[middle]
+        } else {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn band() {
+        let band = Band::around(240.0, 0.25);
+        assert!(band.contains(240.0));
+        assert!(band.contains(180.0));
+        assert!(band.contains(300.0));
+        assert!(!band.contains(179.0));
+        assert!(!band.contains(301.0));
+        assert!(!band.contains(0.0));
+    }
+
+    #[test]
+    fn median_rejects_spikes() {
+        let mut filter = Filter::<3>::new(1.0);
+        assert_eq!(filter.update(1500.0), 1500.0);
+        assert_eq!(filter.update(1510.0), 1505.0);
+        assert_eq!(filter.update(2400.0), 1510.0);
+        assert_eq!(filter.update(1490.0), 1510.0);
+        assert_eq!(filter.update(1520.0), 1520.0);
+        // The spike has left the window
+        assert_eq!(filter.update(1530.0), 1520.0);
+    }
+
+    #[test]
+    fn average_smooths_steps() {
+        let mut filter = Filter::<1>::new(0.5);
+        assert_eq!(filter.update(1000.0), 1000.0);
+        assert_eq!(filter.update(2000.0), 1500.0);
+        assert_eq!(filter.update(2000.0), 1750.0);
+        assert_eq!(filter.update(2000.0), 1875.0);
+    }
+
+    #[test]
+    fn reset_forgets() {
+        let mut filter = Filter::<3>::new(0.5);
+        filter.update(1000.0);
+        filter.update(1000.0);
+        filter.reset();
+        assert_eq!(filter.update(2000.0), 2000.0);
+    }
+}
@@ -0,0 +1,12 @@
+//! The parts of the STM32F4 PWM monitor that do not touch the hardware, kept apart from the RTIC
+//! application so they can be tested on the host with `cargo test`.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod calibration;
+pub mod filter;
+pub mod pid;
+pub mod range;
+pub mod rc;
+pub mod telemetry;
+pub mod watchdog;
@@ -0,0 +1,147 @@
+//! PID controller for closing the loop around a turret position.
+//!
+//! The controller runs at a fixed rate, so it takes the time step in seconds with each update.
+//! Its output is clamped to a range, and the integral stops growing while the output is pinned
+//! at a limit (anti-windup), so it does not overshoot for ages once the turret catches up.
+
+/// Gains of the three terms, per unit of error (e.g. degrees)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gains {
+    pub kp: f32,
+    /// per second of accumulated error
+    pub ki: f32,
+    /// per unit of error change per second
+    pub kd: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pid {
+    gains: Gains,
+    output_min: f32,
+    output_max: f32,
+    /// Integral term, already multiplied by `ki`, so changing gains does not make it jump
+    integral: f32,
+    /// Measurement of the previous update, for the derivative
+    previous: Option<f32>,
+}
+
+impl Pid {
+    pub const fn new(gains: Gains, output_min: f32, output_max: f32) -> Self {
+        Pid {
+            gains,
+            output_min,
+            output_max,
+            integral: 0.0,
+            previous: None,
+        }
+    }
+
+    /// Start over, e.g. after the measurement was lost for a while
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.previous = None;
+    }
+
+    /// Work out the output for the latest `measurement`, `dt_s` seconds after the previous one
+    pub fn update(&mut self, setpoint: f32, measurement: f32, dt_s: f32) -> f32 {
+        let error = setpoint - measurement;
+
+        // Derivative of the measurement rather than of the error, so a setpoint change does not
+        // kick the output
+        let derivative = match self.previous {
+            Some(previous) if dt_s > 0.0 => -(measurement - previous) / dt_s,
+            _ => 0.0,
+        };
+        self.previous = Some(measurement);
+
+        let proportional = self.gains.kp * error;
+        let damping = self.gains.kd * derivative;
+        let integral = self.integral + self.gains.ki * error * dt_s;
+        let output = proportional + integral + damping;
+
+        // Only integrate while that does not push the output further into a limit
+        let winding_up =
+            (output > self.output_max && error > 0.0) || (output < self.output_min && error < 0.0);
+        if !winding_up {
+            self.integral = integral.clamp(self.output_min, self.output_max);
+        }
+        (proportional + self.integral + damping).clamp(self.output_min, self.output_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P: Gains = Gains {
+        kp: 0.1,
+        ki: 0.0,
+        kd: 0.0,
+    };
+
+    #[test]
+    fn proportional_is_limited() {
+        let mut pid = Pid::new(P, -1.0, 1.0);
+        assert_eq!(pid.update(5.0, 0.0, 0.01), 0.5);
+        assert_eq!(pid.update(-5.0, 0.0, 0.01), -0.5);
+        assert_eq!(pid.update(50.0, 0.0, 0.01), 1.0);
+        assert_eq!(pid.update(-50.0, 0.0, 0.01), -1.0);
+    }
+
+    #[test]
+    fn integral_accumulates() {
+        let gains = Gains {
+            kp: 0.0,
+            ki: 1.0,
+            kd: 0.0,
+        };
+        let mut pid = Pid::new(gains, -10.0, 10.0);
+        assert_eq!(pid.update(1.0, 0.0, 0.5), 0.5);
+        assert_eq!(pid.update(1.0, 0.0, 0.5), 1.0);
+        assert_eq!(pid.update(-1.0, 0.0, 0.5), 0.5);
+    }
+
+    #[test]
+    fn anti_windup() {
+        let gains = Gains {
+            kp: 0.5,
+            ki: 1.0,
+            kd: 0.0,
+        };
+        let mut pid = Pid::new(gains, -1.0, 1.0);
+        // Far off for a long time: the output is pinned, the integral must not keep growing
+        for _ in 0..1000 {
+            assert_eq!(pid.update(10.0, 0.0, 0.01), 1.0);
+        }
+        // Overshoot by a little, and the output has to turn around straight away
+        assert!(pid.update(10.0, 10.5, 0.01) < 1.0);
+        assert!(pid.update(10.0, 12.0, 0.01) < 0.0);
+    }
+
+    #[test]
+    fn derivative_damps_movement() {
+        let gains = Gains {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.1,
+        };
+        let mut pid = Pid::new(gains, -1.0, 1.0);
+        assert_eq!(pid.update(0.0, 0.0, 0.1), 0.0);
+        // Moving up at 1 unit/s pushes back, a setpoint change does not kick
+        assert_eq!(pid.update(0.0, 0.1, 0.1), -0.1);
+        assert_eq!(pid.update(100.0, 0.1, 0.1), 0.0);
+    }
+
+    #[test]
+    fn reset_clears_history() {
+        let gains = Gains {
+            kp: 0.0,
+            ki: 1.0,
+            kd: 1.0,
+        };
+        let mut pid = Pid::new(gains, -10.0, 10.0);
+        pid.update(1.0, 0.0, 1.0);
+        pid.reset();
+        assert_eq!(pid.update(0.0, 5.0, 1.0), -5.0);
+    }
+}
@@ -0,0 +1,268 @@
+//! Auto-ranging of a PWM input timer's prescaler.
+//!
+//! The 16 bit capture registers cover a period of 65536 timer ticks, so the prescaler trades
+//! the lowest frequency that can be measured for resolution. [`AutoRange`] starts out coarse,
+//! with room for the lowest frequency expected, measures the actual period, and asks for the
+//! finest prescaler that still has room for the signal to slow down to the bottom of its band.
+//! Once the signal stays out of that band, it starts over from the coarse prescaler.
+//!
+//! A signal that slows down past the bottom of the band no longer fits in the counter. Its
+//! period reads back modulo 65536 ticks, which can land anywhere, the band included. The capture
+//! task watches for the counter overflowing instead, and reports it with
+//! [`AutoRange::overflow`], which starts over right away.
+
+use crate::calibration;
+use crate::filter::Band;
+
+/// Ticks in the longest period the capture registers hold
+const MAX_TICKS: f32 = 65535.0;
+
+/// How many captures in a row outside the band it takes to re-range, rather than being
+/// dropped as glitches
+const RERANGE_AFTER: u8 = 8;
+
+/// What to do with a capture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Range {
+    /// Use it
+    InBand,
+    /// Drop it
+    Glitch,
+    /// Drop it, and set this prescaler
+    Reconfigure(u16),
+}
+
+/// Picks the prescaler of one PWM input timer
+#[derive(Clone, Copy, Debug)]
+pub struct AutoRange {
+    timer_clock_hz: u32,
+    lowest_hz: u32,
+    coarse: u16,
+    prescaler: u16,
+    tolerance: f32,
+    /// Frequencies expected at the current prescaler, `None` while still coarse
+    band: Option<Band>,
+    /// The prescaler just changed, and the next capture still spans the change
+    settling: bool,
+    out_of_band: u8,
+}
+
+impl AutoRange {
+    /// Start at the prescaler `Timer::pwm_input` picks for `lowest_hz`. Once ranged, captures
+    /// more than a `tolerance` fraction off the measured frequency are out of band.
+    pub fn new(timer_clock_hz: u32, lowest_hz: u32, tolerance: f32) -> Self {
+        let coarse = ((timer_clock_hz / lowest_hz - 1) / (1 << 16)) as u16;
+        AutoRange {
+            timer_clock_hz,
+            lowest_hz,
+            coarse,
+            prescaler: coarse,
+            tolerance,
+            band: None,
+            settling: false,
+            out_of_band: 0,
+        }
+    }
+
+    /// Rate the timer counts at with the current prescaler
+    pub fn tick_hz(&self) -> u32 {
+        self.timer_clock_hz / (self.prescaler as u32 + 1)
+    }
+
+    /// Frequencies expected, once ranged
+    pub fn band(&self) -> Option<Band> {
+        self.band
+    }
+
+    /// Lowest frequency a valid capture may have: the bottom of the band once ranged, or else
+    /// the lowest one expected at all. Rounded down.
+    pub fn lowest_hz(&self) -> u32 {
+        self.band
+            .map_or(self.lowest_hz, |band| (band.min_hz as u32).max(1))
+    }
+
+    /// Finest prescaler that fits a whole period at the bottom of the band around `frequency_hz`
+    fn best_prescaler(&self, frequency_hz: f32) -> u16 {
+        let lowest_hz = frequency_hz * (1.0 - self.tolerance);
+        let divider = self.timer_clock_hz as f32 / (MAX_TICKS * lowest_hz);
+        // Rounded up, there is no `ceil` without std
+        let mut ticks = divider as u32;
+        if (ticks as f32) < divider {
+            ticks += 1;
+        }
+        (ticks.max(1) - 1).min(u16::MAX as u32) as u16
+    }
+
+    fn set_prescaler(&mut self, prescaler: u16, band: Option<Band>) -> Range {
+        self.band = band;
+        self.out_of_band = 0;
+        if prescaler == self.prescaler {
+            return Range::Glitch;
+        }
+        self.prescaler = prescaler;
+        self.settling = true;
+        Range::Reconfigure(prescaler)
+    }
+
+    /// The counter overflowed since the last capture: the signal is too slow for the current
+    /// prescaler, and the capture is garbage. Drop it, and go back to coarse.
+    pub fn overflow(&mut self) -> Range {
+        self.settling = false;
+        self.set_prescaler(self.coarse, None)
+    }
+
+    /// Take the period of a valid capture, and decide what to do about it
+    pub fn capture(&mut self, period_clocks: u32) -> Range {
+        if self.settling {
+            self.settling = false;
+            return Range::Glitch;
+        }
+        let frequency_hz = calibration::frequency_hz(period_clocks, self.tick_hz());
+
+        match self.band {
+            None => {
+                let band = Band::around(frequency_hz, self.tolerance);
+                self.set_prescaler(self.best_prescaler(frequency_hz), Some(band))
+            }
+            Some(band) if band.contains(frequency_hz) => {
+                self.out_of_band = 0;
+                Range::InBand
+            }
+            Some(_) => {
+                self.out_of_band += 1;
+                if self.out_of_band < RERANGE_AFTER {
+                    return Range::Glitch;
+                }
+                // The signal moved, measure it again from scratch
+                self.set_prescaler(self.coarse, None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_HZ: u32 = 16_000_000;
+
+    /// Ticks in a period of a `frequency_hz` signal at the current prescaler
+    fn ticks(range: &AutoRange, frequency_hz: f32) -> u32 {
+        (range.tick_hz() as f32 / frequency_hz) as u32
+    }
+
+    /// The same period, as the 16 bit capture registers hold it
+    fn period(range: &AutoRange, frequency_hz: f32) -> u32 {
+        ticks(range, frequency_hz) % 65536
+    }
+
+    /// What the capture task does with a period of a `frequency_hz` signal: report an overflow
+    /// if it does not fit in the counter, or else the capture
+    fn feed(range: &mut AutoRange, frequency_hz: f32) -> Range {
+        if ticks(range, frequency_hz) > 65535 {
+            range.overflow()
+        } else {
+            range.capture(period(range, frequency_hz))
+        }
+    }
+
+    /// Feed captures of a `frequency_hz` signal until it settles in band
+    fn settle(range: &mut AutoRange, frequency_hz: f32) {
+        for _ in 0..20 {
+            if feed(range, frequency_hz) == Range::InBand {
+                return;
+            }
+        }
+        panic!("never settled at {} Hz", frequency_hz);
+    }
+
+    #[test]
+    fn starts_like_the_hal() {
+        let range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        assert_eq!(range.tick_hz(), calibration::tick_hz(CLOCK_HZ, 20));
+        assert_eq!(range.band(), None);
+    }
+
+    #[test]
+    fn ranges_to_the_finest_prescaler() {
+        let mut range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        let coarse_hz = range.tick_hz();
+
+        // The measurement at the coarse prescaler asks for a finer one, and the capture after
+        // that straddles the change
+        let first = range.capture(period(&range, 240.0));
+        assert_eq!(first, Range::Reconfigure(1));
+        assert_eq!(range.tick_hz(), 8_000_000);
+        assert!(range.tick_hz() > coarse_hz);
+        assert_eq!(range.capture(12345), Range::Glitch);
+        assert_eq!(range.capture(period(&range, 240.0)), Range::InBand);
+
+        // The whole band still fits in the capture registers
+        assert!(ticks(&range, 240.0 * 0.8) <= 65535);
+    }
+
+    #[test]
+    fn glitches_do_not_rerange() {
+        let mut range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        settle(&mut range, 240.0);
+        for _ in 0..RERANGE_AFTER - 1 {
+            assert_eq!(range.capture(period(&range, 1000.0)), Range::Glitch);
+        }
+        assert_eq!(range.capture(period(&range, 240.0)), Range::InBand);
+    }
+
+    #[test]
+    fn reranges_when_the_signal_speeds_up() {
+        let mut range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        settle(&mut range, 240.0);
+        let fine_hz = range.tick_hz();
+
+        // Sped up to 1 kHz: back to coarse, then to a prescaler for 1 kHz
+        let mut steps = Vec::new();
+        for _ in 0..RERANGE_AFTER + 3 {
+            steps.push(feed(&mut range, 1000.0));
+        }
+        assert!(steps[..RERANGE_AFTER as usize - 1]
+            .iter()
+            .all(|&step| step == Range::Glitch));
+        assert_eq!(steps[RERANGE_AFTER as usize - 1], Range::Reconfigure(12));
+        settle(&mut range, 1000.0);
+        assert!(range.tick_hz() > fine_hz);
+        assert!(range.band().unwrap().contains(1000.0));
+    }
+
+    #[test]
+    fn reranges_when_the_signal_slows_down() {
+        let mut range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        settle(&mut range, 240.0);
+        let fine_hz = range.tick_hz();
+
+        // 50 Hz overflows the counter at the fine prescaler, and would alias into the band
+        assert!(ticks(&range, 50.0) > 65535);
+        assert!(range
+            .band()
+            .unwrap()
+            .contains(range.tick_hz() as f32 / period(&range, 50.0) as f32));
+
+        // Slowed down to 50 Hz: straight back to coarse, then to a prescaler for 50 Hz
+        assert_eq!(feed(&mut range, 50.0), Range::Reconfigure(12));
+        settle(&mut range, 50.0);
+        assert!(range.tick_hz() < fine_hz);
+        assert!(ticks(&range, 50.0 * 0.8) <= 65535);
+        assert!(range.band().unwrap().contains(50.0));
+    }
+
+    #[test]
+    fn overflow_at_the_coarse_prescaler_is_dropped() {
+        let mut range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        assert_eq!(feed(&mut range, 10.0), Range::Glitch);
+        assert_eq!(range.band(), None);
+    }
+
+    #[test]
+    fn fast_signals_use_no_prescaler() {
+        let mut range = AutoRange::new(CLOCK_HZ, 20, 0.2);
+        settle(&mut range, 5000.0);
+        assert_eq!(range.tick_hz(), CLOCK_HZ);
+    }
+}
@@ -0,0 +1,355 @@
+//! Decoding of hobby RC receiver outputs that carry many channels on one line.
+//!
+//! - PPM: a pulse per channel, where the time from one pulse to the next is the channel's pulse
+//!   width, and a long gap between frames. The firmware timestamps the pulses with timer input
+//!   capture and feeds the intervals to [`Ppm`].
+//! - SBUS: a 100 kBd 8E2 serial stream, inverted, of 25 byte frames with 16 channels of 11 bits.
+//!   The firmware feeds the bytes from a USART to [`Sbus`].
+//!
+//! Both produce a [`Frame`] of pulse widths in µs, which a [`Receiver`] turns into the same per
+//! channel positions the PWM inputs publish.
+
+use crate::calibration::Calibration;
+use crate::watchdog::{TurretPosition, Watchdog};
+
+/// Most channels a frame can carry, SBUS's 16
+pub const MAX_CHANNELS: usize = 16;
+
+/// Pulse widths of the channels in one frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pulses_us: [f32; MAX_CHANNELS],
+    len: usize,
+}
+
+impl Frame {
+    const EMPTY: Frame = Frame {
+        pulses_us: [0.0; MAX_CHANNELS],
+        len: 0,
+    };
+
+    /// Pulse widths in µs, one per channel
+    pub fn pulses_us(&self) -> &[f32] {
+        &self.pulses_us[..self.len]
+    }
+}
+
+/// PPM intervals at least this long are the gap between frames
+const PPM_SYNC_US: f32 = 2700.0;
+
+/// Range of PPM intervals that are a channel, anything else outside a sync is a glitch
+const PPM_CHANNEL_US: core::ops::RangeInclusive<f32> = 700.0..=2300.0;
+
+/// Splits PPM pulse intervals into frames
+#[derive(Clone, Copy, Debug)]
+pub struct Ppm {
+    frame: Frame,
+    /// A sync gap was seen, and no glitch since
+    synced: bool,
+}
+
+impl Default for Ppm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppm {
+    pub const fn new() -> Self {
+        Ppm {
+            frame: Frame::EMPTY,
+            synced: false,
+        }
+    }
+
+    /// Take the time from one pulse to the next, and return the frame that a sync gap finished
+    pub fn interval(&mut self, interval_us: f32) -> Option<Frame> {
+        if interval_us >= PPM_SYNC_US {
+            let frame = core::mem::replace(&mut self.frame, Frame::EMPTY);
+            let complete = self.synced && frame.len > 0;
+            self.synced = true;
+            return complete.then_some(frame);
+        }
+
+        if self.synced && PPM_CHANNEL_US.contains(&interval_us) && self.frame.len < MAX_CHANNELS {
+            self.frame.pulses_us[self.frame.len] = interval_us;
+            self.frame.len += 1;
+        } else {
+            // Drop the rest of this frame, the channels would be off by one
+            self.synced = false;
+        }
+        None
+    }
+}
+
+/// Length of an SBUS frame on the wire
+const SBUS_FRAME_LEN: usize = 25;
+const SBUS_HEADER: u8 = 0x0F;
+const SBUS_FOOTER: u8 = 0x00;
+/// Flags byte: the receiver missed a frame from the transmitter
+const SBUS_FRAME_LOST: u8 = 1 << 2;
+/// Flags byte: the receiver lost the transmitter and sends its failsafe positions
+const SBUS_FAILSAFE: u8 = 1 << 3;
+
+/// A decoded SBUS frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SbusFrame {
+    /// All 16 channels
+    pub frame: Frame,
+    /// The receiver missed the transmitter's latest frame, and repeats the previous one
+    pub frame_lost: bool,
+    /// The receiver lost the transmitter, and the channels are its failsafe positions
+    pub failsafe: bool,
+}
+
+/// Pulse width an SBUS channel value stands for: 172 to 1811 span 988 µs to 2012 µs
+pub fn sbus_pulse_us(value: u16) -> f32 {
+    880.0 + value as f32 * 0.625
+}
+
+/// Splits a stream of SBUS bytes into frames
+#[derive(Clone, Copy, Debug)]
+pub struct Sbus {
+    buffer: [u8; SBUS_FRAME_LEN],
+    len: usize,
+}
+
+impl Default for Sbus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sbus {
+    pub const fn new() -> Self {
+        Sbus {
+            buffer: [0; SBUS_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Start over at the next header, e.g. after a framing or parity error
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    /// Take the next byte, and return the frame it finished
+    pub fn byte(&mut self, byte: u8) -> Option<SbusFrame> {
+        if self.len == 0 && byte != SBUS_HEADER {
+            return None;
+        }
+        self.buffer[self.len] = byte;
+        self.len += 1;
+        if self.len < SBUS_FRAME_LEN {
+            return None;
+        }
+
+        if byte != SBUS_FOOTER {
+            // Out of step, a channel byte happened to look like a header. Try again from the
+            // next one that does.
+            let next = self.buffer[1..]
+                .iter()
+                .position(|&byte| byte == SBUS_HEADER);
+            self.len = match next {
+                Some(next) => {
+                    self.buffer.copy_within(next + 1.., 0);
+                    SBUS_FRAME_LEN - 1 - next
+                }
+                None => 0,
+            };
+            return None;
+        }
+        self.len = 0;
+
+        // 16 channels of 11 bits, least significant bit first
+        let mut frame = Frame {
+            pulses_us: [0.0; MAX_CHANNELS],
+            len: MAX_CHANNELS,
+        };
+        let mut bits = 0u32;
+        let mut count = 0;
+        let mut channel = 0;
+        for &byte in &self.buffer[1..23] {
+            bits |= (byte as u32) << count;
+            count += 8;
+            if count >= 11 {
+                frame.pulses_us[channel] = sbus_pulse_us((bits & 0x7FF) as u16);
+                bits >>= 11;
+                count -= 11;
+                channel += 1;
+            }
+        }
+
+        let flags = self.buffer[23];
+        Some(SbusFrame {
+            frame,
+            frame_lost: flags & SBUS_FRAME_LOST != 0,
+            failsafe: flags & SBUS_FAILSAFE != 0,
+        })
+    }
+}
+
+/// The per channel positions of one receiver, as published to the rest of the application
+#[derive(Clone, Copy, Debug)]
+pub struct Receiver {
+    pub positions: [TurretPosition; MAX_CHANNELS],
+    watchdog: Watchdog,
+}
+
+impl Receiver {
+    /// All channels lost until the first frame, and again when frames stop for as long as the
+    /// `watchdog` allows
+    pub fn new(watchdog: Watchdog) -> Self {
+        Receiver {
+            positions: [TurretPosition::Lost { since: 0 }; MAX_CHANNELS],
+            watchdog,
+        }
+    }
+
+    /// Publish the channels of a frame received at `now`. A pulse width outside the
+    /// calibration keeps the channel's previous position, and channels missing from the frame
+    /// are lost.
+    pub fn frame(&mut self, frame: &Frame, calibration: &Calibration, now: u32) {
+        self.watchdog.capture(now);
+        for (channel, position) in self.positions.iter_mut().enumerate() {
+            match frame.pulses_us().get(channel) {
+                Some(&pulse_us) => {
+                    let angle = calibration.angle(pulse_us);
+                    if angle.valid {
+                        *position = TurretPosition::Position(angle.degrees);
+                    }
+                }
+                None => lose(position, now),
+            }
+        }
+    }
+
+    /// The receiver itself reports losing the transmitter
+    pub fn failsafe(&mut self, now: u32) {
+        self.watchdog.capture(now);
+        self.positions
+            .iter_mut()
+            .for_each(|position| lose(position, now));
+    }
+
+    /// Flag all channels as lost if frames stopped coming in
+    pub fn check(&mut self, now: u32) {
+        if let Some(since) = self.watchdog.lost_since(now) {
+            self.positions
+                .iter_mut()
+                .for_each(|position| lose(position, since));
+        }
+    }
+}
+
+/// Flag a position as lost, unless it already is since earlier
+fn lose(position: &mut TurretPosition, since: u32) {
+    if let TurretPosition::Position(_) = position {
+        *position = TurretPosition::Lost { since };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm_frames() {
+        let mut ppm = Ppm::new();
+        // Joined half way through a frame, that one is dropped
+        assert_eq!(ppm.interval(1500.0), None);
+        assert_eq!(ppm.interval(9000.0), None);
+
+        for pulse in [1000.0, 1500.0, 2000.0] {
+            assert_eq!(ppm.interval(pulse), None);
+        }
+        let frame = ppm.interval(9000.0).unwrap();
+        assert_eq!(frame.pulses_us(), &[1000.0, 1500.0, 2000.0]);
+
+        // A glitch drops the frame it is in
+        ppm.interval(1200.0);
+        ppm.interval(150.0);
+        ppm.interval(1800.0);
+        assert_eq!(ppm.interval(9000.0), None);
+        ppm.interval(1100.0);
+        assert_eq!(ppm.interval(9000.0).unwrap().pulses_us(), &[1100.0]);
+    }
+
+    /// Pack channel values into an SBUS frame
+    fn sbus_frame(values: [u16; MAX_CHANNELS], flags: u8) -> [u8; SBUS_FRAME_LEN] {
+        let mut frame = [0; SBUS_FRAME_LEN];
+        frame[0] = SBUS_HEADER;
+        for (channel, &value) in values.iter().enumerate() {
+            for bit in 0..11 {
+                if value & 1 << bit != 0 {
+                    let at = channel * 11 + bit;
+                    frame[1 + at / 8] |= 1 << (at % 8);
+                }
+            }
+        }
+        frame[23] = flags;
+        frame[24] = SBUS_FOOTER;
+        frame
+    }
+
+    /// The first channel's low byte looks like a header
+    const VALUES: [u16; MAX_CHANNELS] = [
+        0x70F, 172, 992, 1811, 0x7FF, 0, 1000, 1100, 1200, 1300, 1400, 1500, 1600, 1700, 300, 400,
+    ];
+
+    #[test]
+    fn sbus_frames() {
+        let mut sbus = Sbus::new();
+        let bytes = sbus_frame(VALUES, SBUS_FAILSAFE);
+        let mut frames = bytes.iter().filter_map(|&byte| sbus.byte(byte));
+        let frame = frames.next().unwrap();
+        assert!(frames.next().is_none());
+
+        let expected: Vec<f32> = VALUES.iter().map(|&value| sbus_pulse_us(value)).collect();
+        assert_eq!(frame.frame.pulses_us(), &expected[..]);
+        assert!(frame.failsafe);
+        assert!(!frame.frame_lost);
+        assert_eq!(sbus_pulse_us(992), 1500.0);
+    }
+
+    #[test]
+    fn sbus_resyncs() {
+        let mut sbus = Sbus::new();
+        // Joined half way through a frame whose channels contain a header byte
+        let bytes = sbus_frame(VALUES, 0);
+        let mut stream: Vec<u8> = bytes[1..].to_vec();
+        stream.extend(sbus_frame(VALUES, SBUS_FRAME_LOST));
+        stream.extend(bytes);
+
+        let frames: Vec<SbusFrame> = stream.iter().filter_map(|&byte| sbus.byte(byte)).collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].frame_lost);
+        assert!(!frames[1].frame_lost);
+    }
+
+    #[test]
+    fn receiver() {
+        let mut receiver = Receiver::new(Watchdog::new(2, 50, 0));
+        let calibration = Calibration::SERVO;
+        let mut ppm = Ppm::new();
+        ppm.interval(9000.0);
+        ppm.interval(1500.0);
+        ppm.interval(2000.0);
+        let frame = ppm.interval(9000.0).unwrap();
+
+        receiver.frame(&frame, &calibration, 10);
+        assert_eq!(receiver.positions[0], TurretPosition::Position(0.0));
+        assert_eq!(receiver.positions[1], TurretPosition::Position(90.0));
+        assert_eq!(receiver.positions[2], TurretPosition::Lost { since: 0 });
+
+        receiver.check(50);
+        assert_eq!(receiver.positions[0], TurretPosition::Position(0.0));
+        receiver.check(51);
+        assert_eq!(receiver.positions[0], TurretPosition::Lost { since: 10 });
+
+        receiver.frame(&frame, &calibration, 60);
+        receiver.failsafe(70);
+        assert_eq!(receiver.positions[1], TurretPosition::Lost { since: 70 });
+        assert_eq!(receiver.positions[2], TurretPosition::Lost { since: 0 });
+    }
+}
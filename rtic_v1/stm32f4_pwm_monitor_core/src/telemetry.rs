@@ -0,0 +1,266 @@
+//! Binary telemetry frames, shared by the firmware sending them and the host decoding them.
+//!
+//! Each frame carries one [`Sample`] of one turret. The payload is little endian:
+//!
+//! ```text
+//! timestamp_ms: u32 | turret: u8 | filtered: position | raw: position | CRC-16: u16
+//! ```
+//!
+//! where a position is a tag byte followed by 4 bytes: 0 and the angle in degrees as `f32`, or 1
+//! and the time the signal was lost as `u32` ms. The CRC is CRC-16/CCITT-FALSE over everything
+//! before it. The payload is COBS encoded, so the frame contains no 0 bytes, and ends in a 0
+//! delimiter. A receiver that starts listening half way through a frame loses that one frame,
+//! and finds the start of the next at the delimiter.
+
+use crate::watchdog::TurretPosition;
+
+/// Encoded sample with its CRC
+const PAYLOAD_LEN: usize = 17;
+
+/// Longest frame on the wire: the payload with COBS's overhead byte, and the delimiter
+pub const MAX_FRAME_LEN: usize = PAYLOAD_LEN + 2;
+
+/// Ends every frame
+pub const DELIMITER: u8 = 0;
+
+const POSITION: u8 = 0;
+const LOST: u8 = 1;
+
+/// What the telemetry reports about a turret at a time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Monotonic time in ms, wrapping around
+    pub timestamp_ms: u32,
+    pub turret: u8,
+    pub filtered: TurretPosition,
+    pub raw: TurretPosition,
+}
+
+/// The frame of a [`Sample`], including the delimiter
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    bytes: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl core::ops::Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Why a frame could not be decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// Not valid COBS, e.g. cut short
+    Cobs,
+    /// Decoded to the wrong number of bytes
+    Length,
+    /// Corrupted on the way
+    Crc,
+    /// A position tag this decoder does not know
+    Tag,
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn encode_position(position: TurretPosition, out: &mut [u8]) {
+    let (tag, value) = match position {
+        TurretPosition::Position(degrees) => (POSITION, degrees.to_le_bytes()),
+        TurretPosition::Lost { since } => (LOST, since.to_le_bytes()),
+    };
+    out[0] = tag;
+    out[1..5].copy_from_slice(&value);
+}
+
+fn decode_position(data: &[u8]) -> Result<TurretPosition, FrameError> {
+    let value = [data[1], data[2], data[3], data[4]];
+    match data[0] {
+        POSITION => Ok(TurretPosition::Position(f32::from_le_bytes(value))),
+        LOST => Ok(TurretPosition::Lost {
+            since: u32::from_le_bytes(value),
+        }),
+        _ => Err(FrameError::Tag),
+    }
+}
+
+impl Sample {
+    /// Write the frame of this sample to `frame`, and return its length including the delimiter
+    pub fn encode(&self, frame: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let mut payload = [0; PAYLOAD_LEN];
+        payload[0..4].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        payload[4] = self.turret;
+        encode_position(self.filtered, &mut payload[5..10]);
+        encode_position(self.raw, &mut payload[10..15]);
+        let crc = crc16(&payload[..15]);
+        payload[15..].copy_from_slice(&crc.to_le_bytes());
+
+        let len = cobs_encode(&payload, &mut frame[..]);
+        frame[len] = DELIMITER;
+        len + 1
+    }
+
+    /// The frame of this sample, including the delimiter
+    pub fn to_frame(&self) -> Frame {
+        let mut bytes = [0; MAX_FRAME_LEN];
+        let len = self.encode(&mut bytes);
+        Frame { bytes, len }
+    }
+
+    /// Read a sample back from a frame, without its delimiter
+    pub fn decode(frame: &[u8]) -> Result<Sample, FrameError> {
+        let mut payload = [0; PAYLOAD_LEN];
+        if cobs_decode(frame, &mut payload)? != PAYLOAD_LEN {
+            return Err(FrameError::Length);
+        }
+        let crc = u16::from_le_bytes([payload[15], payload[16]]);
+        if crc != crc16(&payload[..15]) {
+            return Err(FrameError::Crc);
+        }
+
+        Ok(Sample {
+            timestamp_ms: u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]),
+            turret: payload[4],
+            filtered: decode_position(&payload[5..10])?,
+            raw: decode_position(&payload[10..15])?,
+        })
+    }
+}
+
+/// COBS encode `data` into `out`, which needs room for one more byte per 254 of `data`.
+/// Returns the encoded length, without a delimiter.
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    // Where the code byte of the current block goes, and its value so far
+    let mut code_at = 0;
+    let mut code = 1;
+    let mut len = 1;
+    for &byte in data {
+        if byte != 0 {
+            out[len] = byte;
+            len += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            code_at = len;
+            code = 1;
+            len += 1;
+        }
+    }
+    out[code_at] = code;
+    len
+}
+
+/// COBS decode `data`, without its delimiter, into `out`. Returns the decoded length.
+fn cobs_decode(data: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+    let mut len = 0;
+    let mut push = |byte| {
+        let slot = out.get_mut(len).ok_or(FrameError::Length)?;
+        *slot = byte;
+        len += 1;
+        Ok(())
+    };
+
+    let mut rest = data;
+    while let Some((&code, tail)) = rest.split_first() {
+        if code == 0 || code as usize - 1 > tail.len() {
+            return Err(FrameError::Cobs);
+        }
+        let (block, tail) = tail.split_at(code as usize - 1);
+        for &byte in block {
+            if byte == 0 {
+                return Err(FrameError::Cobs);
+            }
+            push(byte)?;
+        }
+        // A block stands for its bytes and a 0, except for a full block and the last one
+        if code != 0xFF && !tail.is_empty() {
+            push(0)?;
+        }
+        rest = tail;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: Sample = Sample {
+        timestamp_ms: 0x0102_0300,
+        turret: 2,
+        filtered: TurretPosition::Position(-12.5),
+        raw: TurretPosition::Lost { since: 0 },
+    };
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn cobs() {
+        let cases: [(&[u8], &[u8]); 5] = [
+            (&[], &[0x01]),
+            (&[0x00], &[0x01, 0x01]),
+            (&[0x11, 0x22, 0x00, 0x33], &[0x03, 0x11, 0x22, 0x02, 0x33]),
+            (&[0x11, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01]),
+            (&[0x11, 0x22, 0x33], &[0x04, 0x11, 0x22, 0x33]),
+        ];
+        for (data, encoded) in cases {
+            let mut out = [0; 8];
+            let len = cobs_encode(data, &mut out);
+            assert_eq!(&out[..len], encoded);
+            let len = cobs_decode(encoded, &mut out).unwrap();
+            assert_eq!(&out[..len], data);
+        }
+    }
+
+    #[test]
+    fn cobs_full_block() {
+        let data: Vec<u8> = (1..=254).collect();
+        let mut out = [0; 256];
+        let len = cobs_encode(&data, &mut out);
+        let mut decoded = [0; 256];
+        let len = cobs_decode(&out[..len], &mut decoded).unwrap();
+        assert_eq!(&decoded[..len], &data[..]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = SAMPLE.to_frame();
+        assert!(frame.len() <= MAX_FRAME_LEN);
+        assert_eq!(frame.last(), Some(&DELIMITER));
+        assert!(!frame[..frame.len() - 1].contains(&DELIMITER));
+        assert_eq!(Sample::decode(&frame[..frame.len() - 1]), Ok(SAMPLE));
+    }
+
+    #[test]
+    fn corruption_is_caught() {
+        let frame = SAMPLE.to_frame();
+        let body = &frame[..frame.len() - 1];
+        for i in 0..body.len() {
+            for bit in 0..8 {
+                let mut corrupt = body.to_vec();
+                corrupt[i] ^= 1 << bit;
+                assert!(Sample::decode(&corrupt).is_err(), "byte {} bit {}", i, bit);
+            }
+        }
+        // Cut short, e.g. the receiver started listening half way through
+        assert!(Sample::decode(&body[3..]).is_err());
+        assert_eq!(Sample::decode(&[]), Err(FrameError::Length));
+    }
+}
@@ -0,0 +1,117 @@
+//! Signal-loss detection for the turret PWM input.
+//!
+//! The capture interrupt only fires while there is a signal, so on its own a disconnected line
+//! would leave the last position in place forever. A periodic task asks the [`Watchdog`] how
+//! long ago the last valid capture was, and flags the signal as lost after a number of missed
+//! periods. Times are in ms from the monotonic timer, and wrap around.
+
+/// Turret position as published to the rest of the application
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurretPosition {
+    /// Angle in degrees from the latest valid capture
+    Position(f32),
+    /// No valid capture since this time
+    Lost { since: u32 },
+}
+
+/// Tracks the time of the last valid capture
+#[derive(Clone, Copy, Debug)]
+pub struct Watchdog {
+    periods: u32,
+    timeout_ms: u32,
+    last_capture: u32,
+}
+
+impl Watchdog {
+    /// Flag the signal as lost once `periods` periods of a `frequency_hz` signal went by
+    /// without a valid capture. Starts out as if there was a capture at `now`.
+    pub fn new(periods: u32, frequency_hz: u32, now: u32) -> Self {
+        let mut watchdog = Watchdog {
+            periods,
+            timeout_ms: 0,
+            last_capture: now,
+        };
+        watchdog.set_frequency(frequency_hz);
+        watchdog
+    }
+
+    /// The signal is now expected at `frequency_hz`, e.g. once its timer ranged to it
+    pub fn set_frequency(&mut self, frequency_hz: u32) {
+        // At least one tick, rounded up so a late capture is not taken for a lost signal
+        self.timeout_ms = (self.periods * 1000).div_ceil(frequency_hz).max(1);
+    }
+
+    /// How long the signal may be silent before it counts as lost, which is also how often
+    /// it is worth checking
+    pub fn timeout_ms(&self) -> u32 {
+        self.timeout_ms
+    }
+
+    /// A valid capture happened at `now`
+    pub fn capture(&mut self, now: u32) {
+        self.last_capture = now;
+    }
+
+    /// Time of the last valid capture, if that was too long before `now`
+    pub fn lost_since(&self, now: u32) -> Option<u32> {
+        (now.wrapping_sub(self.last_capture) > self.timeout_ms).then_some(self.last_capture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::range::{AutoRange, Range};
+
+    #[test]
+    fn timeout() {
+        // 5 periods at 240 Hz are 20.8 ms
+        assert_eq!(Watchdog::new(5, 240, 0).timeout_ms(), 21);
+        assert_eq!(Watchdog::new(1, 50, 0).timeout_ms(), 20);
+        assert_eq!(Watchdog::new(1, 100_000, 0).timeout_ms(), 1);
+    }
+
+    #[test]
+    fn flags_missing_captures() {
+        let mut watchdog = Watchdog::new(2, 100, 1000);
+        assert_eq!(watchdog.lost_since(1020), None);
+        assert_eq!(watchdog.lost_since(1021), Some(1000));
+
+        // Captures keep it happy, and a new one clears the loss
+        watchdog.capture(1030);
+        assert_eq!(watchdog.lost_since(1045), None);
+        assert_eq!(watchdog.lost_since(1100), Some(1030));
+        watchdog.capture(1100);
+        assert_eq!(watchdog.lost_since(1100), None);
+    }
+
+    #[test]
+    fn follows_the_range() {
+        let mut range = AutoRange::new(16_000_000, 20, 0.2);
+        let mut watchdog = Watchdog::new(5, range.lowest_hz(), 0);
+        assert_eq!(watchdog.timeout_ms(), 250);
+
+        // Ranged to 240 Hz: 5 periods at the bottom of the band, 192 Hz
+        let period = |range: &AutoRange, frequency_hz: u32| range.tick_hz() / frequency_hz;
+        assert!(matches!(
+            range.capture(period(&range, 240)),
+            Range::Reconfigure(_)
+        ));
+        watchdog.set_frequency(range.lowest_hz());
+        assert_eq!(watchdog.timeout_ms(), 27);
+        assert_eq!(watchdog.lost_since(30), Some(0));
+
+        // Re-ranging allows for the lowest frequency again
+        range.overflow();
+        watchdog.set_frequency(range.lowest_hz());
+        assert_eq!(watchdog.timeout_ms(), 250);
+        assert_eq!(watchdog.lost_since(30), None);
+    }
+
+    #[test]
+    fn timer_wraps_around() {
+        let watchdog = Watchdog::new(2, 100, u32::MAX - 5);
+        assert_eq!(watchdog.lost_since(10), None);
+        assert_eq!(watchdog.lost_since(20), Some(u32::MAX - 5));
+    }
+}
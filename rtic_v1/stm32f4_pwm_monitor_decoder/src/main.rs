@@ -0,0 +1,150 @@
+//! Turns a capture of the STM32F4 PWM monitor's telemetry into CSV, one row per sample.
+//!
+//! ```text
+//! stty -F /dev/ttyACM0 115200 raw
+//! cat /dev/ttyACM0 > capture.bin
+//! pwm-monitor-decoder capture.bin > turrets.csv
+//! ```
+//!
+//! Without a file it reads stdin. Angles are empty while a turret's signal is lost, and
+//! `lost_since_ms` tells since when.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use pwm_monitor_core::telemetry::{Sample, DELIMITER};
+use pwm_monitor_core::watchdog::TurretPosition;
+
+const USAGE: &str = "usage: pwm-monitor-decoder [CAPTURE.bin]";
+
+const HEADER: &str = "timestamp_ms,turret,filtered_degrees,raw_degrees,lost_since_ms";
+
+/// How much of a capture made sense
+#[derive(Debug, Default, PartialEq)]
+struct Stats {
+    samples: usize,
+    bad_frames: usize,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let capture = match args {
+        [] => {
+            let mut capture = Vec::new();
+            io::stdin()
+                .read_to_end(&mut capture)
+                .map_err(|err| format!("stdin: {}", err))?;
+            capture
+        }
+        [input] => fs::read(input).map_err(|err| format!("{}: {}", input, err))?,
+        _ => return Err(USAGE.into()),
+    };
+
+    let stdout = io::stdout();
+    let stats = decode(&capture, &mut stdout.lock()).map_err(|err| format!("stdout: {}", err))?;
+    eprintln!("{} samples, {} bad frames", stats.samples, stats.bad_frames);
+    Ok(())
+}
+
+/// Write the CSV of all complete frames in `capture`. The first frame is usually cut short,
+/// as the capture started half way through it, and counts as bad. What follows the last
+/// delimiter is not complete yet, and is left out.
+fn decode(capture: &[u8], csv: &mut impl Write) -> io::Result<Stats> {
+    let mut stats = Stats::default();
+    writeln!(csv, "{}", HEADER)?;
+
+    let mut frames: Vec<&[u8]> = capture.split(|&byte| byte == DELIMITER).collect();
+    frames.pop();
+    for frame in frames {
+        match Sample::decode(frame) {
+            Ok(sample) => {
+                write_row(&sample, csv)?;
+                stats.samples += 1;
+            }
+            Err(_) => stats.bad_frames += 1,
+        }
+    }
+    Ok(stats)
+}
+
+fn write_row(sample: &Sample, csv: &mut impl Write) -> io::Result<()> {
+    let degrees = |position| match position {
+        TurretPosition::Position(degrees) => degrees.to_string(),
+        TurretPosition::Lost { .. } => String::new(),
+    };
+    let lost_since = [sample.filtered, sample.raw]
+        .into_iter()
+        .find_map(|position| match position {
+            TurretPosition::Lost { since } => Some(since.to_string()),
+            TurretPosition::Position(_) => None,
+        })
+        .unwrap_or_default();
+    writeln!(
+        csv,
+        "{},{},{},{},{}",
+        sample.timestamp_ms,
+        sample.turret,
+        degrees(sample.filtered),
+        degrees(sample.raw),
+        lost_since
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_to_csv() {
+        let first = Sample {
+            timestamp_ms: 1000,
+            turret: 0,
+            filtered: TurretPosition::Position(12.5),
+            raw: TurretPosition::Position(13.0),
+        }
+        .to_frame();
+        let second = Sample {
+            timestamp_ms: 1020,
+            turret: 1,
+            filtered: TurretPosition::Lost { since: 250 },
+            raw: TurretPosition::Lost { since: 250 },
+        }
+        .to_frame();
+
+        // Starts half way through a frame, and ends half way through another
+        let mut capture = first[5..].to_vec();
+        capture.extend_from_slice(&first);
+        capture.extend_from_slice(&second);
+        capture.extend_from_slice(&first[..7]);
+
+        let mut csv = Vec::new();
+        let stats = decode(&capture, &mut csv).unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                samples: 2,
+                bad_frames: 1
+            }
+        );
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!("{}\n1000,0,12.5,13,\n1020,1,,,250\n", HEADER)
+        );
+    }
+
+    #[test]
+    fn empty_capture() {
+        let mut csv = Vec::new();
+        assert_eq!(decode(&[], &mut csv).unwrap(), Stats::default());
+        assert_eq!(csv, format!("{}\n", HEADER).into_bytes());
+    }
+}